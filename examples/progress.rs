@@ -10,7 +10,7 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Progress {
   file_size: Arc<Mutex<usize>>,
   downloaded: Arc<Mutex<usize>>,
@@ -25,11 +25,11 @@ impl Progress {
   }
 
   async fn get_file_size(&self) -> usize {
-    self.file_size.lock().await.clone()
+    *self.file_size.lock().await
   }
 
   async fn get_progess(&self) -> usize {
-    self.downloaded.lock().await.clone()
+    *self.downloaded.lock().await
   }  
 }
 
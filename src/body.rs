@@ -2,12 +2,12 @@ use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::Bytes;
 use futures_core::Stream;
 use http_body::Body as HttpBody;
-use pin_project_lite::pin_project;
-use tokio::time::Sleep;
+use tokio::time::{Instant, Sleep};
 
 /// An asynchronous request body.
 pub struct Body {
@@ -17,6 +17,8 @@ pub struct Body {
 // The `Stream` trait isn't stable, so the impl isn't public.
 pub(crate) struct ImplStream(Body);
 
+// Only the decoders create reusable bodies
+#[cfg_attr(not(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate")), allow(dead_code))]
 enum Inner {
     Reusable(Bytes),
     Streaming {
@@ -27,83 +29,51 @@ enum Inner {
                     + Sync,
             >,
         >,
-        timeout: Option<Pin<Box<Sleep>>>,
+        timeout: Option<ReadTimeout>,
     },
 }
 
-pin_project! {
-    struct WrapStream<S> {
-        #[pin]
-        inner: S,
-    }
+/// Fails a body when the server doesn't send the next chunk within the read timeout.
+///
+/// The timer only runs while the body waits for the server, not while the chunks are being consumed.
+struct ReadTimeout {
+    duration: Duration,
+    /// Expires `duration` after the body started waiting, `None` until it first had to wait.
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// Whether the body is waiting for the next chunk, so `sleep` is running.
+    waiting: bool,
 }
 
-struct WrapHyper(hyper::Body);
+struct WrapHyper<B>(B);
 
 impl Body {
-    /// Wrap a futures `Stream` in a box inside `Body`.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// # use reqwest::Body;
-    /// # use futures_util;
-    /// # fn main() {
-    /// let chunks: Vec<Result<_, ::std::io::Error>> = vec![
-    ///     Ok("hello"),
-    ///     Ok(" "),
-    ///     Ok("world"),
-    /// ];
-    ///
-    /// let stream = futures_util::stream::iter(chunks);
-    ///
-    /// let body = Body::wrap_stream(stream);
-    /// # }
-    /// ```
-    ///
-    /// # Optional
-    ///
-    /// This requires the `stream` feature to be enabled.
-    #[cfg(feature = "stream")]
-    pub fn wrap_stream<S>(stream: S) -> Body
-    where
-        S: futures_core::stream::TryStream + Send + Sync + 'static,
-        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-        Bytes: From<S::Ok>,
-    {
-        Body::stream(stream)
-    }
-
-    #[cfg(feature = "blocking")]
-    pub(crate) fn wrap(body: hyper::Body) -> Body {
-        Body {
-            inner: Inner::Streaming {
-                body: Box::pin(WrapHyper(body)),
-                timeout: None,
-            },
-        }
-    }
-
+    #[cfg_attr(not(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate")), allow(dead_code))]
     pub(crate) fn empty() -> Body {
         Body::reusable(Bytes::new())
     }
 
+    #[cfg_attr(not(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate")), allow(dead_code))]
     pub(crate) fn reusable(chunk: Bytes) -> Body {
         Body {
             inner: Inner::Reusable(chunk),
         }
     }
 
-    pub(crate) fn into_stream(self) -> ImplStream {
-        ImplStream(self)
+    /// Wraps a hyper body, failing with `Timeout::Read` when the next chunk takes longer than `read_timeout`.
+    pub(crate) fn with_read_timeout<B>(body: B, read_timeout: Option<Duration>) -> Body
+    where
+        B: HttpBody<Data = Bytes, Error = hyper::Error> + Unpin + Send + Sync + 'static,
+    {
+        Body {
+            inner: Inner::Streaming {
+                body: Box::pin(WrapHyper(body)),
+                timeout: read_timeout.map(|duration| ReadTimeout { duration, sleep: None, waiting: false }),
+            },
+        }
     }
 
-    #[cfg(feature = "multipart")]
-    pub(crate) fn content_length(&self) -> Option<u64> {
-        match self.inner {
-            Inner::Reusable(ref bytes) => Some(bytes.len() as u64),
-            Inner::Streaming { ref body, .. } => body.size_hint().exact(),
-        }
+    pub(crate) fn into_stream(self) -> ImplStream {
+        ImplStream(self)
     }
 }
 
@@ -125,6 +95,24 @@ impl fmt::Debug for Body {
     }
 }
 
+// ===== impl ReadTimeout =====
+
+impl ReadTimeout {
+    /// Polls the timer of a body that has to wait for the next chunk, starting it when the body just started waiting.
+    fn poll_expired(&mut self, cx: &mut Context) -> bool {
+        if !self.waiting {
+            self.waiting = true;
+            let deadline = Instant::now() + self.duration;
+            if let Some(sleep) = self.sleep.as_mut() {
+                sleep.as_mut().reset(deadline);
+            } else {
+                self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline)));
+            }
+        }
+        self.sleep.as_mut().is_some_and(|sleep| sleep.as_mut().poll(cx).is_ready())
+    }
+}
+
 // ===== impl ImplStream =====
 
 impl HttpBody for ImplStream {
@@ -140,13 +128,21 @@ impl HttpBody for ImplStream {
                 ref mut body,
                 ref mut timeout,
             } => {
-                if let Some(ref mut timeout) = timeout {
-                    if let Poll::Ready(()) = timeout.as_mut().poll(cx) {
-                        return Poll::Ready(Some(Err(crate::error::Error::TimedOut())));
+                // A chunk that has already been received is returned, however long it wasn't asked for
+                let opt_try_chunk = match Pin::new(body).poll_data(cx) {
+                    Poll::Ready(opt_try_chunk) => opt_try_chunk,
+                    Poll::Pending => {
+                        if timeout.as_mut().is_some_and(|timeout| timeout.poll_expired(cx)) {
+                            return Poll::Ready(Some(Err(crate::error::Error::TimedOut(crate::error::Timeout::Read))));
+                        }
+                        return Poll::Pending;
                     }
+                };
+                // Every chunk restarts the read timeout
+                if let Some(timeout) = timeout {
+                    timeout.waiting = false;
                 }
-                futures_core::ready!(Pin::new(body).poll_data(cx))
-                    .map(|opt_chunk| opt_chunk.map(Into::into).map_err(|e| crate::error::Error::InvalidBody(e)))
+                opt_try_chunk.map(|opt_chunk| opt_chunk.map_err(crate::error::Error::InvalidBody))
             }
             Inner::Reusable(ref mut bytes) => {
                 if bytes.is_empty() {
@@ -194,37 +190,9 @@ impl Stream for ImplStream {
     }
 }
 
-// ===== impl WrapStream =====
-
-impl<S, D, E> HttpBody for WrapStream<S>
-where
-    S: Stream<Item = Result<D, E>>,
-    D: Into<Bytes>,
-    E: Into<Box<dyn std::error::Error + Send + Sync>>,
-{
-    type Data = Bytes;
-    type Error = E;
-
-    fn poll_data(
-        self: Pin<&mut Self>,
-        cx: &mut Context,
-    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
-        let item = futures_core::ready!(self.project().inner.poll_next(cx)?);
-
-        Poll::Ready(item.map(|val| Ok(val.into())))
-    }
-
-    fn poll_trailers(
-        self: Pin<&mut Self>,
-        _cx: &mut Context,
-    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
-        Poll::Ready(Ok(None))
-    }
-}
-
 // ===== impl WrapHyper =====
 
-impl HttpBody for WrapHyper {
+impl<B: HttpBody<Data = Bytes, Error = hyper::Error> + Unpin> HttpBody for WrapHyper<B> {
     type Data = Bytes;
     type Error = Box<dyn std::error::Error + Send + Sync>;
 
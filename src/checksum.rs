@@ -0,0 +1,347 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use base64::Engine as _;
+use bytes::Bytes;
+use http::HeaderMap;
+use http_body::Body as HttpBody;
+use sha2::Digest as _;
+use crate::error::Error;
+
+/// A hash algorithm the downloaded bytes can be verified with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Sha1,
+    Md5,
+}
+
+/// A digest the downloaded bytes are expected to have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExpectedDigest {
+    pub(crate) algorithm: Algorithm,
+    /// The expected digest as lowercase hex.
+    pub(crate) expected: String,
+}
+
+/// Hashes the downloaded bytes as they are written, to compare them with an `ExpectedDigest` afterwards.
+pub(crate) struct Hasher {
+    expected: ExpectedDigest,
+    inner: Inner,
+}
+
+enum Inner {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Sha1(sha1::Sha1),
+    Md5(md5::Md5),
+}
+
+/// Hashes the chunks of a response body as they are received, before they are decoded.
+pub(crate) struct HashedBody {
+    body: hyper::Body,
+    hashers: Arc<Mutex<Vec<Hasher>>>,
+}
+
+impl ExpectedDigest {
+    pub(crate) fn new(algorithm: Algorithm, expected: &[u8]) -> Self {
+        Self {
+            algorithm,
+            expected: to_hex(expected),
+        }
+    }
+
+    /// Reads a hex encoded digest, failing with `Error::InvalidDigest` when it isn't a digest of `algorithm`.
+    pub(crate) fn from_hex(algorithm: Algorithm, expected: &str) -> Result<Self, Error> {
+        let expected = expected.trim().to_ascii_lowercase();
+        if !expected.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(Error::InvalidDigest(format!("`{}` isn't hex encoded", expected)));
+        }
+        if expected.len() != algorithm.len() * 2 {
+            return Err(Error::InvalidDigest(format!("a {:?} digest has {} hex digits, `{}` has {}", algorithm, algorithm.len() * 2, expected, expected.len())));
+        }
+        Ok(Self { algorithm, expected })
+    }
+}
+
+impl Algorithm {
+    /// The length of the digests in bytes.
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+            Algorithm::Sha1 => 20,
+            Algorithm::Md5 => 16,
+        }
+    }
+}
+
+impl Hasher {
+    pub(crate) fn new(expected: ExpectedDigest) -> Self {
+        let inner = match expected.algorithm {
+            Algorithm::Sha256 => Inner::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Inner::Sha512(sha2::Sha512::new()),
+            Algorithm::Sha1 => Inner::Sha1(sha1::Sha1::new()),
+            Algorithm::Md5 => Inner::Md5(md5::Md5::new()),
+        };
+        Self { expected, inner }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self.inner {
+            Inner::Sha256(ref mut hasher) => hasher.update(bytes),
+            Inner::Sha512(ref mut hasher) => hasher.update(bytes),
+            Inner::Sha1(ref mut hasher) => hasher.update(bytes),
+            Inner::Md5(ref mut hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// Compares the digest of the hashed bytes with the expected digest.
+    pub(crate) fn verify(self) -> Result<(), Error> {
+        let actual = match self.inner {
+            Inner::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Inner::Sha512(hasher) => to_hex(&hasher.finalize()),
+            Inner::Sha1(hasher) => to_hex(&hasher.finalize()),
+            Inner::Md5(hasher) => to_hex(&hasher.finalize()),
+        };
+        if actual == self.expected.expected {
+            Ok(())
+        } else {
+            Err(Error::ChecksumMismatch {
+                algorithm: self.expected.algorithm,
+                expected: self.expected.expected,
+                actual,
+            })
+        }
+    }
+}
+
+impl HashedBody {
+    pub(crate) fn new(body: hyper::Body, hashers: Arc<Mutex<Vec<Hasher>>>) -> Self {
+        Self { body, hashers }
+    }
+}
+
+impl HttpBody for HashedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let chunk = futures_core::ready!(Pin::new(&mut self.body).poll_data(cx));
+        if let Some(Ok(ref chunk)) = chunk {
+            if let Ok(mut hashers) = self.hashers.lock() {
+                for hasher in hashers.iter_mut() {
+                    hasher.update(chunk);
+                }
+            }
+        }
+        Poll::Ready(chunk)
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        HttpBody::size_hint(&self.body)
+    }
+}
+
+/// Parses the digests the server sent in the `Repr-Digest`, `Content-Digest`, `Digest` and `Content-MD5` headers.
+///
+/// All of them cover the bytes as they are sent, before any content encoding is decoded.
+/// `Repr-Digest` and `Digest` cover the whole representation, so they are ignored for a `partial` response.
+pub(crate) fn from_headers(headers: &HeaderMap, partial: bool) -> Vec<ExpectedDigest> {
+    let mut digests = Vec::new();
+    // RFC 9530, a dictionary of byte sequences like `sha-256=:base64:`
+    let mut fields = vec!["content-digest"];
+    if !partial {
+        fields.push("repr-digest");
+    }
+    for field in fields {
+        for (name, value) in header_values(headers, field).flat_map(dictionary) {
+            match value.strip_prefix(':').and_then(|value| value.strip_suffix(':')) {
+                Some(value) => digests.extend(parse(field, name, value)),
+                None => log::warn!("Ignoring {} {} that isn't a byte sequence", field, name),
+            }
+        }
+    }
+    // RFC 3230, a list of `algorithm=base64`
+    if !partial {
+        for (name, value) in header_values(headers, "digest").flat_map(dictionary) {
+            digests.extend(parse("digest", name, value));
+        }
+    }
+    // RFC 1864, the base64 of the MD5 digest
+    for value in header_values(headers, "content-md5") {
+        digests.extend(parse("content-md5", "md5", value));
+    }
+    digests
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers.get_all(name).iter().filter_map(|value| value.to_str().ok())
+}
+
+/// Splits a comma separated list of `name=value` pairs, ignoring parameters after a `;`.
+fn dictionary(value: &str) -> impl Iterator<Item = (&str, &str)> {
+    value.split(',').filter_map(|member| {
+        let member = member.split(';').next()?;
+        let (name, value) = member.split_once('=')?;
+        Some((name.trim(), value.trim()))
+    })
+}
+
+/// Parses a base64 encoded digest, ignoring unsupported algorithms and invalid digests.
+fn parse(field: &str, name: &str, value: &str) -> Option<ExpectedDigest> {
+    let algorithm = match name.to_ascii_lowercase().as_str() {
+        "sha-256" => Algorithm::Sha256,
+        "sha-512" => Algorithm::Sha512,
+        "sha" => Algorithm::Sha1,
+        "md5" => Algorithm::Md5,
+        _ => {
+            log::debug!("Ignoring {} with the unsupported algorithm {}", field, name);
+            return None;
+        }
+    };
+    match base64::engine::general_purpose::STANDARD.decode(value) {
+        Ok(digest) => Some(ExpectedDigest::new(algorithm, &digest)),
+        Err(error) => {
+            log::warn!("Ignoring {} {} that isn't valid base64: {}", field, name, error);
+            None
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    const BODY: &[u8] = b"hello world";
+
+    fn base64(digest: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(digest)
+    }
+
+    fn headers(fields: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn sha256() -> ExpectedDigest {
+        ExpectedDigest::new(Algorithm::Sha256, &sha2::Sha256::digest(BODY))
+    }
+
+    fn sha512() -> ExpectedDigest {
+        ExpectedDigest::new(Algorithm::Sha512, &sha2::Sha512::digest(BODY))
+    }
+
+    fn md5() -> ExpectedDigest {
+        ExpectedDigest::new(Algorithm::Md5, &md5::Md5::digest(BODY))
+    }
+
+    #[test]
+    fn byte_sequences() {
+        let value = format!("sha-256=:{}:, sha-512=:{}:", base64(&sha2::Sha256::digest(BODY)), base64(&sha2::Sha512::digest(BODY)));
+        assert_eq!(from_headers(&headers(&[("content-digest", &value)]), false), vec![sha256(), sha512()]);
+        assert_eq!(from_headers(&headers(&[("repr-digest", &value)]), false), vec![sha256(), sha512()]);
+        // Parameters are ignored
+        let value = format!("sha-256=:{}:;param=1", base64(&sha2::Sha256::digest(BODY)));
+        assert_eq!(from_headers(&headers(&[("content-digest", &value)]), false), vec![sha256()]);
+    }
+
+    #[test]
+    fn bare_values() {
+        let digest = base64(&sha2::Sha256::digest(BODY));
+        // RFC 9530 only has byte sequences
+        assert_eq!(from_headers(&headers(&[("content-digest", &format!("sha-256={}", digest))]), false), vec![]);
+        assert_eq!(from_headers(&headers(&[("repr-digest", &format!("sha-256={}", digest))]), false), vec![]);
+        // RFC 3230 only has bare values, and the algorithms are case-insensitive
+        assert_eq!(from_headers(&headers(&[("digest", &format!("SHA-256={}", digest))]), false), vec![sha256()]);
+        assert_eq!(from_headers(&headers(&[("digest", &format!("sha-256=:{}:", digest))]), false), vec![]);
+        assert_eq!(from_headers(&headers(&[("content-md5", &base64(&md5::Md5::digest(BODY)))]), false), vec![md5()]);
+    }
+
+    #[test]
+    fn unknown_algorithms_and_invalid_digests() {
+        let value = format!("unixsum=30637, sha-384=:AAAA:, sha-256=:{}:, sha-512=:not base64!:", base64(&sha2::Sha256::digest(BODY)));
+        assert_eq!(from_headers(&headers(&[("repr-digest", &value)]), false), vec![sha256()]);
+        assert_eq!(from_headers(&headers(&[("digest", "UNIXsum=30637, crc32c=AAAA")]), false), vec![]);
+        assert_eq!(from_headers(&headers(&[("content-md5", "not base64!")]), false), vec![]);
+    }
+
+    #[test]
+    fn partial_responses() {
+        let sha256_value = format!("sha-256=:{}:", base64(&sha2::Sha256::digest(BODY)));
+        let sha512_value = format!("sha-512=:{}:", base64(&sha2::Sha512::digest(BODY)));
+        let fields = [
+            ("repr-digest", sha512_value.as_str()),
+            ("digest", "sha-512=AAAA"),
+            ("content-digest", sha256_value.as_str()),
+        ];
+        // The digests of the whole representation don't apply to a range of it
+        assert_eq!(from_headers(&headers(&fields), true), vec![sha256()]);
+        assert_eq!(from_headers(&headers(&fields), false).len(), 3);
+    }
+
+    #[test]
+    fn repeated_fields() {
+        let fields = [
+            ("repr-digest", format!("sha-256=:{}:", base64(&sha2::Sha256::digest(BODY)))),
+            ("repr-digest", format!("sha-512=:{}:", base64(&sha2::Sha512::digest(BODY)))),
+            ("content-md5", base64(&md5::Md5::digest(BODY))),
+        ];
+        let fields: Vec<_> = fields.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        assert_eq!(from_headers(&headers(&fields), false), vec![sha256(), sha512(), md5()]);
+    }
+
+    #[test]
+    fn verify() {
+        let mut hasher = Hasher::new(sha256());
+        hasher.update(&BODY[..5]);
+        hasher.update(&BODY[5..]);
+        assert!(hasher.verify().is_ok());
+
+        let mut hasher = Hasher::new(ExpectedDigest::from_hex(Algorithm::Md5, " 5EB63BBBE01EEED093CB22BB8F5ACDC3 ").unwrap());
+        hasher.update(BODY);
+        assert!(hasher.verify().is_ok());
+
+        let mut hasher = Hasher::new(sha256());
+        hasher.update(b"hello wOrld");
+        match hasher.verify() {
+            Err(Error::ChecksumMismatch { algorithm, expected, actual }) => {
+                assert_eq!(algorithm, Algorithm::Sha256);
+                assert_eq!(expected, sha256().expected);
+                assert_eq!(actual, to_hex(&sha2::Sha256::digest(b"hello wOrld")));
+            },
+            result => panic!("expected Error::ChecksumMismatch, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_hex_digests() {
+        assert_eq!(ExpectedDigest::from_hex(Algorithm::Sha256, &to_hex(&sha2::Sha256::digest(BODY))).unwrap(), sha256());
+        for (algorithm, expected) in [
+            (Algorithm::Md5, "5eb63bbbe01eeed093cb22bb8f5acdcg"),
+            (Algorithm::Md5, "5eb63bbbe01eeed093cb22bb8f5acd"),
+            (Algorithm::Sha256, "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+            (Algorithm::Sha1, ""),
+            (Algorithm::Sha512, "sha512-deadbeef"),
+        ] {
+            assert!(matches!(ExpectedDigest::from_hex(algorithm, expected), Err(Error::InvalidDigest(_))), "{}", expected);
+        }
+    }
+}
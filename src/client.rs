@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use http::header;
+use hyper::client::{Client, ResponseFuture};
+use crate::builder::Downloader;
+use crate::dns::{AddressPreference, Resolve, ResolverService, SharedResolver, SocketAddrs};
+use crate::error::Error;
+use crate::proxy::{Proxies, Proxy, ProxyConnector};
+use crate::tcp::TcpConnector;
+use crate::tls::{Certificate, HttpsConnector, Identity, TlsOptions};
+
+pub(crate) type Connector = HttpsConnector;
+
+/// A long-lived client that keeps connections open, so they can be reused by many downloads.
+///
+/// Cloning a `DownloadClient` is cheap, the clones share the same connection pool.
+///
+/// # Examples
+///
+/// ```
+/// extern crate tokio;
+/// extern crate download_async;
+///
+/// #[tokio::main]
+/// async fn main() {
+///   let client = download_async::DownloadClient::new().unwrap();
+///   for _ in 0..3 {
+///     let mut downloader = client.downloader();
+///     downloader.use_uri(download_async::http::Uri::from_static("https://www.example.com"));
+///     let mut buffer = vec![];
+///     let response = downloader.download(download_async::Body::empty(), &mut buffer).await;
+///   }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct DownloadClient {
+  client: Client<Connector, hyper::Body>,
+  /// The proxies the connections are made through.
+  proxies: Arc<Proxies>
+}
+
+/// Configures the connection pool and the connections of a `DownloadClient`.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadClientBuilder {
+  /// How long an idle connection is kept open, hyper's default is used when unset.
+  pool_idle_timeout: Option<Option<Duration>>,
+  /// The maximum amount of idle connections per host, unlimited when unset.
+  pool_max_idle_per_host: Option<usize>,
+  /// If set to true, every connection uses HTTP/2 so downloads from the same host share a single connection.
+  http2_only: bool,
+  /// The maximum time to establish a connection.
+  connect_timeout: Option<Duration>,
+  /// Resolves the hosts, the system resolver is used when unset.
+  pub(crate) resolver: Option<SharedResolver>,
+  /// The addresses to use for a host instead of resolving it.
+  pub(crate) dns_overrides: HashMap<String, Vec<SocketAddr>>,
+  /// Which IP versions are connected to, and which one is tried first.
+  pub(crate) address_preference: AddressPreference,
+  /// How long to wait before trying the other IP version, hyper's default is used when unset.
+  pub(crate) happy_eyeballs_timeout: Option<Option<Duration>>,
+  /// The local IP address the connections are made from.
+  pub(crate) local_address: Option<IpAddr>,
+  /// The network interface the connections are bound to.
+  pub(crate) device: Option<String>,
+  /// The root certificates and identity of the TLS connections.
+  pub(crate) tls: TlsOptions,
+  /// The proxies the connections are made through.
+  pub(crate) proxies: Proxies
+}
+
+impl std::fmt::Debug for DownloadClient {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("DownloadClient").finish()
+  }
+}
+
+impl DownloadClient {
+  /// Creates a `DownloadClient` with the default settings.
+  ///
+  /// Fails with `Error::TlsError` when the TLS backend can't be initialized.
+  pub fn new() -> Result<Self, Error> {
+    DownloadClientBuilder::default().build()
+  }
+
+  /// Creates a `DownloadClientBuilder` to configure a `DownloadClient`.
+  pub fn builder() -> DownloadClientBuilder {
+    DownloadClientBuilder::default()
+  }
+
+  /// Creates a `Downloader` that downloads over the connections of this client.
+  ///
+  /// The connection settings of the client replace those of the `Downloader`, like `use_sockets` and `connect_timeout`.
+  pub fn downloader(&self) -> Downloader {
+    let mut downloader = Downloader::new();
+    downloader.use_client(self.clone());
+    downloader
+  }
+
+  pub(crate) fn request(&self, mut request: http::Request<hyper::Body>) -> ResponseFuture {
+    // Plain http is sent to an http proxy itself, otherwise the proxy authenticates when connecting
+    if request.uri().scheme() == Some(&http::uri::Scheme::HTTP) {
+      if let Some(authorization) = self.proxies.for_uri(request.uri()).filter(|proxy| proxy.forwards_http()).and_then(Proxy::authorization) {
+        request.headers_mut().insert(header::PROXY_AUTHORIZATION, authorization);
+      }
+    }
+    self.client.request(request)
+  }
+}
+
+impl DownloadClientBuilder {
+  /// Sets how long an idle connection is kept open, `None` keeping them open until the server closes them.
+  pub fn pool_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+    self.pool_idle_timeout = Some(timeout);
+    self
+  }
+
+  /// Sets the maximum amount of idle connections that are kept open per host.
+  pub fn pool_max_idle_per_host(&mut self, max_idle: usize) -> &mut Self {
+    self.pool_max_idle_per_host = Some(max_idle);
+    self
+  }
+
+  /// Only uses HTTP/2, which multiplexes all downloads from the same host over a single connection.
+  ///
+  /// The servers must support HTTP/2 without negotiating it first.
+  pub fn http2_only(&mut self, http2_only: bool) -> &mut Self {
+    self.http2_only = http2_only;
+    self
+  }
+
+  /// Sets the maximum time to establish a connection.
+  pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+    self.connect_timeout = Some(timeout);
+    self
+  }
+
+  /// Connects to `sockets` instead of resolving the host of the URI.
+  pub fn use_sockets(&mut self, sockets: SocketAddrs) -> &mut Self {
+    self.dns_resolver(sockets)
+  }
+
+  /// Resolves the hosts with `resolver` instead of the system resolver, see `Downloader::dns_resolver`.
+  pub fn dns_resolver<R: Resolve + 'static>(&mut self, resolver: R) -> &mut Self {
+    self.resolver = Some(SharedResolver(Arc::new(resolver)));
+    self
+  }
+
+  /// Connects to `addr` for `host` without resolving it, see `Downloader::resolve`.
+  pub fn resolve(&mut self, host: &str, addr: SocketAddr) -> &mut Self {
+    self.dns_overrides.entry(host.to_ascii_lowercase()).or_default().push(addr);
+    self
+  }
+
+  /// Sets which IP versions are connected to, and which one is tried first, see `Downloader::address_preference`.
+  pub fn address_preference(&mut self, preference: AddressPreference) -> &mut Self {
+    self.address_preference = preference;
+    self
+  }
+
+  /// Sets how long to wait for a connection before trying the other IP version, see `Downloader::happy_eyeballs_timeout`.
+  pub fn happy_eyeballs_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+    self.happy_eyeballs_timeout = Some(timeout);
+    self
+  }
+
+  /// Makes the connections from `address`, see `Downloader::local_address`.
+  pub fn local_address(&mut self, address: IpAddr) -> &mut Self {
+    self.local_address = Some(address);
+    self
+  }
+
+  /// Binds the connections to the network interface `device`, see `Downloader::bind_device`.
+  #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+  pub fn bind_device(&mut self, device: &str) -> &mut Self {
+    self.device = Some(device.to_string());
+    self
+  }
+
+  /// Trusts `certificate` as a root certificate, see `Downloader::add_root_certificate`.
+  pub fn add_root_certificate(&mut self, certificate: Certificate) -> &mut Self {
+    self.tls.root_certificates.push(certificate);
+    self
+  }
+
+  /// Sets whether the built-in root certificates are trusted, see `Downloader::tls_built_in_roots`.
+  pub fn tls_built_in_roots(&mut self, built_in_roots: bool) -> &mut Self {
+    self.tls.built_in_roots = built_in_roots;
+    self
+  }
+
+  /// Authenticates to servers that ask for a client certificate with `identity`, see `Downloader::identity`.
+  pub fn identity(&mut self, identity: Identity) -> &mut Self {
+    self.tls.identity = Some(identity);
+    self
+  }
+
+  /// Only accepts the public key with the SHA-256 hash `sha256` for `host`, see `Downloader::pin_public_key`.
+  pub fn pin_public_key(&mut self, host: &str, sha256: &str) -> &mut Self {
+    let sha256 = sha256.strip_prefix("sha256/").unwrap_or(sha256);
+    self.tls.pins.entry(host.to_ascii_lowercase()).or_default().push(sha256.to_string());
+    self
+  }
+
+  /// Accepts any certificate, see `Downloader::danger_accept_invalid_certs`.
+  pub fn danger_accept_invalid_certs(&mut self, accept_invalid_certs: bool) -> &mut Self {
+    self.tls.accept_invalid_certs = accept_invalid_certs;
+    self
+  }
+
+  /// Accepts certificates for other hosts, see `Downloader::danger_accept_invalid_hostnames`.
+  pub fn danger_accept_invalid_hostnames(&mut self, accept_invalid_hostnames: bool) -> &mut Self {
+    self.tls.accept_invalid_hostnames = accept_invalid_hostnames;
+    self
+  }
+
+  /// Makes every connection through `proxy`.
+  pub fn proxy(&mut self, proxy: Proxy) -> &mut Self {
+    self.proxies = Proxies::all(proxy);
+    self
+  }
+
+  /// Makes the connections through the proxies in the `http_proxy`, `HTTPS_PROXY` and `ALL_PROXY` environment variables,
+  /// except to the hosts in `NO_PROXY`, see `Downloader::proxy_from_env`.
+  pub fn proxy_from_env(&mut self) -> &mut Self {
+    self.proxies = Proxies::from_env();
+    self
+  }
+
+  /// Creates the `DownloadClient`.
+  ///
+  /// Fails with `Error::TlsError` when the TLS backend can't be initialized, or can't use the identity.
+  pub fn build(&self) -> Result<DownloadClient, Error> {
+    let resolver_service = ResolverService::new(self.resolver.clone(), self.dns_overrides.clone(), self.address_preference);
+    let mut tcp_connector = TcpConnector::new(resolver_service, self.device.clone(), self.local_address, self.connect_timeout);
+    if let Some(timeout) = self.happy_eyeballs_timeout {
+      tcp_connector.set_happy_eyeballs_timeout(timeout);
+    }
+    let proxies = Arc::new(self.proxies.clone());
+    let proxy_connector = ProxyConnector::new(tcp_connector, proxies.clone());
+    // Whether only https is allowed is checked for every request instead
+    let https_connector = HttpsConnector::new(proxy_connector, self.http2_only, &self.tls)?;
+
+    let mut builder = Client::builder();
+    if let Some(timeout) = self.pool_idle_timeout {
+      builder.pool_idle_timeout(timeout);
+    }
+    if let Some(max_idle) = self.pool_max_idle_per_host {
+      builder.pool_max_idle_per_host(max_idle);
+    }
+    builder.http2_only(self.http2_only);
+    Ok(DownloadClient {
+      client: builder.build(https_connector),
+      proxies
+    })
+  }
+}
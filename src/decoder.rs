@@ -1,4 +1,5 @@
 use std::fmt;
+#[cfg(any(feature = "gzip", feature = "brotli", feature = "zstd", feature = "deflate"))]
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -9,18 +10,22 @@ use async_compression::tokio::bufread::GzipDecoder;
 #[cfg(feature = "brotli")]
 use async_compression::tokio::bufread::BrotliDecoder;
 
+#[cfg(feature = "zstd")]
+use async_compression::tokio::bufread::ZstdDecoder;
+
 #[cfg(feature = "deflate")]
 use async_compression::tokio::bufread::ZlibDecoder;
 
 use bytes::Bytes;
 use futures_core::Stream;
+#[cfg(any(feature = "gzip", feature = "brotli", feature = "zstd", feature = "deflate"))]
 use futures_util::stream::Peekable;
 use http::HeaderMap;
 use hyper::body::HttpBody;
 
-#[cfg(any(feature = "gzip", feature = "brotli", feature = "deflate"))]
+#[cfg(any(feature = "gzip", feature = "brotli", feature = "zstd", feature = "deflate"))]
 use tokio_util::codec::{BytesCodec, FramedRead};
-#[cfg(any(feature = "gzip", feature = "brotli", feature = "deflate"))]
+#[cfg(any(feature = "gzip", feature = "brotli", feature = "zstd", feature = "deflate"))]
 use tokio_util::io::StreamReader;
 
 use crate::body::Body;
@@ -32,6 +37,8 @@ pub(super) struct Accepts {
     pub(super) gzip: bool,
     #[cfg(feature = "brotli")]
     pub(super) brotli: bool,
+    #[cfg(feature = "zstd")]
+    pub(super) zstd: bool,
     #[cfg(feature = "deflate")]
     pub(super) deflate: bool,
 }
@@ -55,25 +62,34 @@ enum Inner {
     #[cfg(feature = "brotli")]
     Brotli(FramedRead<BrotliDecoder<StreamReader<Peekable<IoStream>, Bytes>>, BytesCodec>),
 
+    /// A `Zstd` decoder will uncompress the zstd compressed response content before returning it.
+    #[cfg(feature = "zstd")]
+    Zstd(FramedRead<ZstdDecoder<StreamReader<Peekable<IoStream>, Bytes>>, BytesCodec>),
+
     /// A `Deflate` decoder will uncompress the deflated response content before returning it.
     #[cfg(feature = "deflate")]
     Deflate(FramedRead<ZlibDecoder<StreamReader<Peekable<IoStream>, Bytes>>, BytesCodec>),
 
     /// A decoder that doesn't have a value yet.
-    #[cfg(any(feature = "brotli", feature = "gzip", feature = "deflate"))]
+    #[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
     Pending(Pending),
 }
 
 /// A future attempt to poll the response body for EOF so we know whether to use gzip or not.
+#[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
 struct Pending(Peekable<IoStream>, DecoderType);
 
+#[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
 struct IoStream(super::body::ImplStream);
 
+#[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
 enum DecoderType {
     #[cfg(feature = "gzip")]
     Gzip,
     #[cfg(feature = "brotli")]
     Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
     #[cfg(feature = "deflate")]
     Deflate,
 }
@@ -85,13 +101,6 @@ impl fmt::Debug for Decoder {
 }
 
 impl Decoder {
-    #[cfg(feature = "blocking")]
-    pub(crate) fn empty() -> Decoder {
-        Decoder {
-            inner: Inner::PlainText(Body::empty().into_stream()),
-        }
-    }
-
     /// A plain text decoder.
     ///
     /// This decoder will emit the underlying chunks as-is.
@@ -131,6 +140,21 @@ impl Decoder {
         }
     }
 
+    /// A zstd decoder.
+    ///
+    /// This decoder will buffer and decompress chunks that are zstd compressed.
+    #[cfg(feature = "zstd")]
+    fn zstd(body: Body) -> Decoder {
+        use futures_util::StreamExt;
+
+        Decoder {
+            inner: Inner::Pending(Pending(
+                IoStream(body.into_stream()).peekable(),
+                DecoderType::Zstd,
+            )),
+        }
+    }
+
     /// A deflate decoder.
     ///
     /// This decoder will buffer and decompress chunks that are deflated.
@@ -146,7 +170,7 @@ impl Decoder {
         }
     }
 
-    #[cfg(any(feature = "brotli", feature = "gzip", feature = "deflate"))]
+    #[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
     fn detect_encoding(headers: &mut HeaderMap, encoding_str: &str) -> bool {
         use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
         use log::warn;
@@ -197,6 +221,13 @@ impl Decoder {
             }
         }
 
+        #[cfg(feature = "zstd")]
+        {
+            if _accepts.zstd && Decoder::detect_encoding(_headers, "zstd") {
+                return Decoder::zstd(body);
+            }
+        }
+
         #[cfg(feature = "deflate")]
         {
             if _accepts.deflate && Decoder::detect_encoding(_headers, "deflate") {
@@ -208,10 +239,7 @@ impl Decoder {
     }
 
     pub(super) fn is_encoded(&self) -> bool {
-        match self.inner {
-            Inner::PlainText(_) => false,
-            _ => true
-        }
+        !matches!(self.inner, Inner::PlainText(_))
     }
 }
 
@@ -221,41 +249,47 @@ impl Stream for Decoder {
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         // Do a read or poll for a pending decoder value.
         match self.inner {
-            #[cfg(any(feature = "brotli", feature = "gzip", feature = "deflate"))]
+            #[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
             Inner::Pending(ref mut future) => match Pin::new(future).poll(cx) {
                 Poll::Ready(Ok(inner)) => {
                     self.inner = inner;
-                    return self.poll_next(cx);
-                }
-                Poll::Ready(Err(e)) => {
-                    return Poll::Ready(Some(Err(crate::error::decode_io(e))));
+                    self.poll_next(cx)
                 }
-                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => Poll::Ready(Some(Err(crate::error::decode_io(e)))),
+                Poll::Pending => Poll::Pending,
             },
             Inner::PlainText(ref mut body) => Pin::new(body).poll_next(cx),
             #[cfg(feature = "gzip")]
             Inner::Gzip(ref mut decoder) => {
-                return match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
+                match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
                     Some(Ok(bytes)) => Poll::Ready(Some(Ok(bytes.freeze()))),
                     Some(Err(err)) => Poll::Ready(Some(Err(crate::error::decode_io(err)))),
                     None => Poll::Ready(None),
-                };
+                }
             }
             #[cfg(feature = "brotli")]
             Inner::Brotli(ref mut decoder) => {
-                return match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
+                match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
+                    Some(Ok(bytes)) => Poll::Ready(Some(Ok(bytes.freeze()))),
+                    Some(Err(err)) => Poll::Ready(Some(Err(crate::error::decode_io(err)))),
+                    None => Poll::Ready(None),
+                }
+            }
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut decoder) => {
+                match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
                     Some(Ok(bytes)) => Poll::Ready(Some(Ok(bytes.freeze()))),
                     Some(Err(err)) => Poll::Ready(Some(Err(crate::error::decode_io(err)))),
                     None => Poll::Ready(None),
-                };
+                }
             }
             #[cfg(feature = "deflate")]
             Inner::Deflate(ref mut decoder) => {
-                return match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
+                match futures_core::ready!(Pin::new(decoder).poll_next(cx)) {
                     Some(Ok(bytes)) => Poll::Ready(Some(Ok(bytes.freeze()))),
                     Some(Err(err)) => Poll::Ready(Some(Err(crate::error::decode_io(err)))),
                     None => Poll::Ready(None),
-                };
+                }
             }
         }
     }
@@ -283,12 +317,13 @@ impl HttpBody for Decoder {
         match self.inner {
             Inner::PlainText(ref body) => HttpBody::size_hint(body),
             // the rest are "unknown", so default
-            #[cfg(any(feature = "brotli", feature = "gzip", feature = "deflate"))]
+            #[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
             _ => http_body::SizeHint::default(),
         }
     }
 }
 
+#[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
 impl Future for Pending {
     type Output = Result<Inner, std::io::Error>;
 
@@ -326,6 +361,11 @@ impl Future for Pending {
                 GzipDecoder::new(StreamReader::new(_body)),
                 BytesCodec::new(),
             )))),
+            #[cfg(feature = "zstd")]
+            DecoderType::Zstd => Poll::Ready(Ok(Inner::Zstd(FramedRead::new(
+                ZstdDecoder::new(StreamReader::new(_body)),
+                BytesCodec::new(),
+            )))),
             #[cfg(feature = "deflate")]
             DecoderType::Deflate => Poll::Ready(Ok(Inner::Deflate(FramedRead::new(
                 ZlibDecoder::new(StreamReader::new(_body)),
@@ -335,13 +375,14 @@ impl Future for Pending {
     }
 }
 
+#[cfg(any(feature = "brotli", feature = "zstd", feature = "gzip", feature = "deflate"))]
 impl Stream for IoStream {
     type Item = Result<Bytes, std::io::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         match futures_core::ready!(Pin::new(&mut self.0).poll_next(cx)) {
             Some(Ok(chunk)) => Poll::Ready(Some(Ok(chunk))),
-            Some(Err(err)) => Poll::Ready(Some(Err(std::io::Error::new(std::io::ErrorKind::Other, err)))),
+            Some(Err(err)) => Poll::Ready(Some(Err(std::io::Error::other(err)))),
             None => Poll::Ready(None),
         }
     }
@@ -350,16 +391,14 @@ impl Stream for IoStream {
 // ===== impl Accepts =====
 
 impl Accepts {
-    pub(super) fn as_str(&self) -> Option<&'static str> {
-        match (self.is_gzip(), self.is_brotli(), self.is_deflate()) {
-            (true, true, true) => Some("gzip, br, deflate"),
-            (true, true, false) => Some("gzip, br"),
-            (true, false, true) => Some("gzip, deflate"),
-            (false, true, true) => Some("br, deflate"),
-            (true, false, false) => Some("gzip"),
-            (false, true, false) => Some("br"),
-            (false, false, true) => Some("deflate"),
-            (false, false, false) => None,
+    /// The accepted encodings as the value of an Accept-Encoding header, `None` if no encoding is accepted.
+    pub(super) fn as_str(&self) -> Option<String> {
+        let encodings = [(self.is_gzip(), "gzip"), (self.is_brotli(), "br"), (self.is_zstd(), "zstd"), (self.is_deflate(), "deflate")];
+        let accepted: Vec<&str> = encodings.iter().filter(|(accepted, _)| *accepted).map(|(_, encoding)| *encoding).collect();
+        if accepted.is_empty() {
+            None
+        } else {
+            Some(accepted.join(", "))
         }
     }
 
@@ -387,6 +426,18 @@ impl Accepts {
         }
     }
 
+    fn is_zstd(&self) -> bool {
+        #[cfg(feature = "zstd")]
+        {
+            self.zstd
+        }
+
+        #[cfg(not(feature = "zstd"))]
+        {
+            false
+        }
+    }
+
     fn is_deflate(&self) -> bool {
         #[cfg(feature = "deflate")]
         {
@@ -400,6 +451,8 @@ impl Accepts {
     }
 }
 
+// Without compression features there are no fields to default
+#[allow(clippy::derivable_impls)]
 impl Default for Accepts {
     fn default() -> Accepts {
         Accepts {
@@ -407,6 +460,8 @@ impl Default for Accepts {
             gzip: true,
             #[cfg(feature = "brotli")]
             brotli: true,
+            #[cfg(feature = "zstd")]
+            zstd: true,
             #[cfg(feature = "deflate")]
             deflate: true,
         }
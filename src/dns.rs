@@ -1,9 +1,56 @@
-use std::net::ToSocketAddrs;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::task::Poll;
-use hyper::client::connect::dns::Name;
+use std::time::{Duration, Instant};
+use hyper::client::connect::dns::GaiResolver;
+pub use hyper::client::connect::dns::Name;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// The future returned by `Resolve::resolve`.
+pub type Resolving = Pin<Box<dyn Future<Output = Result<SocketAddrs, BoxError>> + Send>>;
+
+/// Resolves host names to the addresses to connect to.
+///
+/// The port of the URI is used for the returned addresses, their own port is ignored.
+///
+/// # Examples
+///
+/// ```
+/// extern crate tokio;
+/// extern crate download_async;
+///
+/// use download_async::{Name, Resolve, Resolving, SocketAddrs};
+///
+/// struct Table;
+///
+/// impl Resolve for Table {
+///   fn resolve(&self, name: Name) -> Resolving {
+///     let addrs = match name.as_str() {
+///       "www.example.com" => vec![([93, 184, 215, 14], 0).into()],
+///       _ => vec![],
+///     };
+///     Box::pin(async move { Ok(SocketAddrs::from(addrs)) })
+///   }
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///   let mut downloader = download_async::Downloader::new();
+///   downloader.use_uri(download_async::http::Uri::from_static("https://www.example.com"));
+///   downloader.dns_resolver(Table);
+///   let mut buffer = vec![];
+///   let response = downloader.download(download_async::Body::empty(), &mut buffer).await;
+/// }
+/// ```
+pub trait Resolve: Send + Sync {
+  /// Resolves `name` to the addresses to connect to.
+  fn resolve(&self, name: Name) -> Resolving;
+}
 
 
 #[derive(Debug, Clone)]
@@ -40,35 +87,459 @@ impl Iterator for SocketAddrs {
   }
 }
 
+/// Connects to the same addresses, regardless of the requested name.
+impl Resolve for SocketAddrs {
+  fn resolve(&self, _: Name) -> Resolving {
+    let socket_addrs = self.clone();
+    Box::pin(async move {
+      Ok(socket_addrs)
+    })
+  }
+}
+
+/// Resolves names with `getaddrinfo` on a blocking thread.
+#[derive(Clone, Default)]
+pub(crate) struct SystemResolver;
+
+impl Resolve for SystemResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let resolving = tower::Service::call(&mut GaiResolver::new(), name);
+    Box::pin(async move {
+      Ok(SocketAddrs::from(resolving.await?.collect::<Vec<_>>()))
+    })
+  }
+}
+
+/// A resolved host, or the error resolving it, kept until it expires.
+#[derive(Clone)]
+struct CacheEntry {
+  result: Result<Vec<SocketAddr>, String>,
+  expires: Instant
+}
+
+/// Caches the addresses another resolver returns, the system resolver by default.
+///
+/// Cloning a `CachingResolver` is cheap, the clones share the same cache, so it can be given to many downloads and clients.
+/// Failed lookups, and those without addresses, are cached as well, for `negative_ttl`. When the cache holds `max_entries` hosts,
+/// the expired hosts are dropped first, then the host that expires soonest.
+///
+/// # Examples
+///
+/// ```
+/// extern crate tokio;
+/// extern crate download_async;
+///
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() {
+///   let mut cache = download_async::CachingResolver::new();
+///   cache.ttl(Duration::from_secs(300)).max_entries(64);
+///   for _ in 0..3 {
+///     let mut downloader = download_async::Downloader::new();
+///     downloader.use_uri(download_async::http::Uri::from_static("https://www.example.com"));
+///     downloader.dns_resolver(cache.clone());
+///     let mut buffer = vec![];
+///     let response = downloader.download(download_async::Body::empty(), &mut buffer).await;
+///   }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct CachingResolver {
+  /// The resolver the hosts that aren't cached are looked up with.
+  resolver: SharedResolver,
+  /// The resolved hosts, by their lowercase name.
+  cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+  /// How long resolved addresses are kept.
+  ttl: Duration,
+  /// How long failed lookups are kept.
+  negative_ttl: Duration,
+  /// The maximum amount of hosts that are kept.
+  max_entries: usize
+}
+
+impl Default for CachingResolver {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl std::fmt::Debug for CachingResolver {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("CachingResolver")
+      .field("ttl", &self.ttl)
+      .field("negative_ttl", &self.negative_ttl)
+      .field("max_entries", &self.max_entries)
+      .finish()
+  }
+}
+
+impl CachingResolver {
+  /// Creates a `CachingResolver` in front of the system resolver.
+  ///
+  /// Addresses are kept for a minute, failed lookups for 10 seconds and at most 1024 hosts are kept.
+  pub fn new() -> Self {
+    Self::with_resolver(SystemResolver)
+  }
+
+  /// Creates a `CachingResolver` in front of `resolver`.
+  pub fn with_resolver<R: Resolve + 'static>(resolver: R) -> Self {
+    CachingResolver {
+      resolver: SharedResolver(Arc::new(resolver)),
+      cache: Arc::new(Mutex::new(HashMap::new())),
+      ttl: Duration::from_secs(60),
+      negative_ttl: Duration::from_secs(10),
+      max_entries: 1024
+    }
+  }
+
+  /// Sets how long resolved addresses are kept.
+  pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+    self.ttl = ttl;
+    self
+  }
+
+  /// Sets how long failed lookups and those without addresses are kept, `Duration::ZERO` not caching them at all.
+  pub fn negative_ttl(&mut self, ttl: Duration) -> &mut Self {
+    self.negative_ttl = ttl;
+    self
+  }
+
+  /// Sets the maximum amount of hosts that are kept.
+  pub fn max_entries(&mut self, max_entries: usize) -> &mut Self {
+    self.max_entries = max_entries;
+    self
+  }
+
+  /// Forgets every resolved host.
+  pub fn clear(&self) {
+    self.cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+  }
+
+  /// The cached result for `host`, if it hasn't expired.
+  fn cached(&self, host: &str) -> Option<Result<Vec<SocketAddr>, String>> {
+    let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+    match cache.get(host) {
+      Some(entry) if entry.expires > Instant::now() => Some(entry.result.clone()),
+      Some(_) => {
+        cache.remove(host);
+        None
+      }
+      None => None
+    }
+  }
+
+  /// Keeps `result` for `host`, making room for it when the cache is full.
+  fn insert(cache: &Mutex<HashMap<String, CacheEntry>>, max_entries: usize, host: String, entry: CacheEntry) {
+    let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+    if !cache.contains_key(&host) && cache.len() >= max_entries {
+      let now = Instant::now();
+      cache.retain(|_, entry| entry.expires > now);
+      while cache.len() >= max_entries {
+        let soonest = match cache.iter().min_by_key(|(_, entry)| entry.expires) {
+          Some((host, _)) => host.clone(),
+          None => break
+        };
+        cache.remove(&soonest);
+      }
+    }
+    if max_entries > 0 {
+      cache.insert(host, entry);
+    }
+  }
+}
+
+impl Resolve for CachingResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let host = name.as_str().to_ascii_lowercase();
+    if let Some(result) = self.cached(&host) {
+      return Box::pin(async move {
+        result.map(SocketAddrs::from).map_err(BoxError::from)
+      });
+    }
+    let resolving = self.resolver.0.resolve(name);
+    let cache = self.cache.clone();
+    let (ttl, negative_ttl, max_entries) = (self.ttl, self.negative_ttl, self.max_entries);
+    Box::pin(async move {
+      let result = resolving.await;
+      let (entry, ttl) = match &result {
+        Ok(socket_addrs) => {
+          let socket_addrs: Vec<_> = socket_addrs.clone().collect();
+          // A host without addresses is as unusable as one that failed to resolve
+          let ttl = if socket_addrs.is_empty() { negative_ttl } else { ttl };
+          (Ok(socket_addrs), ttl)
+        },
+        Err(error) => (Err(error.to_string()), negative_ttl)
+      };
+      if !ttl.is_zero() {
+        let expires = Instant::now() + ttl;
+        CachingResolver::insert(&cache, max_entries, host, CacheEntry { result: entry, expires });
+      }
+      result
+    })
+  }
+}
+
+/// A `Resolve` implementation that can be shared by the `Downloader` options and the clients.
+#[derive(Clone)]
+pub(crate) struct SharedResolver(pub(crate) Arc<dyn Resolve>);
+
+impl std::fmt::Debug for SharedResolver {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("Resolve").finish()
+  }
+}
+
+/// Which IP versions are connected to, and which one is tried first.
+///
+/// With Happy Eyeballs (RFC 8305), the addresses of the other version are tried when the first ones haven't connected
+/// within the fallback delay, see `Downloader::happy_eyeballs_timeout`. IP addresses in the URI are used as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressPreference {
+  /// Tries the addresses in the order the resolver returned them, the IP version of the first address going first.
+  #[default]
+  Resolver,
+  /// Tries the IPv4 addresses first.
+  PreferIpv4,
+  /// Tries the IPv6 addresses first.
+  PreferIpv6,
+  /// Only connects to IPv4 addresses.
+  Ipv4Only,
+  /// Only connects to IPv6 addresses.
+  Ipv6Only
+}
+
+impl AddressPreference {
+  /// Orders and filters `socket_addrs`, keeping the order the resolver returned within each IP version.
+  fn apply(self, socket_addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (ipv4, ipv6): (Vec<_>, Vec<_>) = socket_addrs.iter().partition(|addr| addr.is_ipv4());
+    match self {
+      AddressPreference::Resolver => socket_addrs,
+      AddressPreference::PreferIpv4 => ipv4.into_iter().chain(ipv6).collect(),
+      AddressPreference::PreferIpv6 => ipv6.into_iter().chain(ipv4).collect(),
+      AddressPreference::Ipv4Only => ipv4,
+      AddressPreference::Ipv6Only => ipv6
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct ResolverService {
-  pub socket_addrs: SocketAddrs
+  /// The addresses to use for a host instead of resolving it.
+  overrides: Arc<HashMap<String, Vec<SocketAddr>>>,
+  /// The resolver for the other hosts.
+  resolver: SharedResolver,
+  /// Which IP versions are connected to, and which one is tried first.
+  preference: AddressPreference,
+  /// The local address the connections are made from, only the addresses of its IP version are kept.
+  local_address: Option<IpAddr>
 }
 
 impl ResolverService {
-  pub fn new(socket_addrs: SocketAddrs) -> Self {
+  /// Creates a `ResolverService` that looks up hosts in `overrides` first, then resolves them with `resolver` or `getaddrinfo`.
+  ///
+  /// The addresses are ordered and filtered by `preference`.
+  pub fn new(resolver: Option<SharedResolver>, overrides: HashMap<String, Vec<SocketAddr>>, preference: AddressPreference) -> Self {
     ResolverService {
-      socket_addrs
+      overrides: Arc::new(overrides),
+      resolver: resolver.unwrap_or_else(|| SharedResolver(Arc::new(SystemResolver))),
+      preference,
+      local_address: None
     }
   }
+
+  /// Only keeps the addresses of the IP version of `local_address`, which can't connect to the other one.
+  pub(crate) fn set_local_address(&mut self, local_address: Option<IpAddr>) {
+    self.local_address = local_address;
+  }
 }
 
 impl tower::Service<Name> for ResolverService {
   type Response = SocketAddrs;
-  type Error = Box<dyn Error + Send + Sync>;
+  type Error = BoxError;
   // We can't "name" an `async` generated future.
-  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send >>;
+  type Future = Resolving;
 
   fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
       // This connector is always ready, but others might not be.
       Poll::Ready(Ok(()))
   }
 
-  fn call(&mut self, _: Name) -> Self::Future {
-    let socket_addrs = self.socket_addrs.clone();
-    let fut = async move { 
-      Ok(socket_addrs) 
+  fn call(&mut self, name: Name) -> Self::Future {
+    let host = name.to_string();
+    let resolving = match self.overrides.get(&host.to_ascii_lowercase()) {
+      Some(socket_addrs) => SocketAddrs::from(socket_addrs.clone()).resolve(name),
+      None => self.resolver.0.resolve(name)
     };
-    Box::pin(fut)
+    let (preference, local_address) = (self.preference, self.local_address);
+    Box::pin(async move {
+      let socket_addrs = resolving.await?.collect::<Vec<_>>();
+      let resolved = !socket_addrs.is_empty();
+      let mut socket_addrs = preference.apply(socket_addrs);
+      if resolved && socket_addrs.is_empty() {
+        return Err(format!("{} has no addresses allowed by {:?}", host, preference).into());
+      }
+      if let Some(local_address) = local_address {
+        socket_addrs.retain(|addr| addr.is_ipv4() == local_address.is_ipv4());
+        if resolved && socket_addrs.is_empty() {
+          return Err(format!("{} has no addresses of the IP version of the local address {}", host, local_address).into());
+        }
+      }
+      Ok(SocketAddrs::from(socket_addrs))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  /// Resolves `fails.test` to an error, `empty.test` to no addresses and the other hosts to `127.0.0.1`, counting the lookups.
+  #[derive(Clone, Default)]
+  struct Counting(Arc<AtomicUsize>);
+
+  impl Counting {
+    fn lookups(&self) -> usize {
+      self.0.load(Ordering::SeqCst)
+    }
+  }
+
+  impl Resolve for Counting {
+    fn resolve(&self, name: Name) -> Resolving {
+      self.0.fetch_add(1, Ordering::SeqCst);
+      Box::pin(async move {
+        match name.as_str() {
+          "fails.test" => Err("no such host".into()),
+          "empty.test" => Ok(SocketAddrs::from(vec![])),
+          _ => Ok(SocketAddrs::from(vec![([127, 0, 0, 1], 0).into()]))
+        }
+      })
+    }
+  }
+
+  async fn resolve(resolver: &CachingResolver, host: &str) -> Result<Vec<SocketAddr>, BoxError> {
+    Ok(resolver.resolve(Name::from_str(host).unwrap()).await?.collect())
+  }
+
+  #[tokio::test]
+  async fn addresses_expire_after_the_ttl() {
+    let counting = Counting::default();
+    let mut cache = CachingResolver::with_resolver(counting.clone());
+    cache.ttl(Duration::from_millis(100));
+    assert_eq!(resolve(&cache, "www.example.com").await.unwrap(), [SocketAddr::from(([127, 0, 0, 1], 0))]);
+    assert_eq!(resolve(&cache, "WWW.example.com").await.unwrap(), [SocketAddr::from(([127, 0, 0, 1], 0))]);
+    assert_eq!(counting.lookups(), 1);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    resolve(&cache, "www.example.com").await.unwrap();
+    assert_eq!(counting.lookups(), 2);
+  }
+
+  #[tokio::test]
+  async fn failures_and_empty_results_expire_after_the_negative_ttl() {
+    let counting = Counting::default();
+    let mut cache = CachingResolver::with_resolver(counting.clone());
+    cache.ttl(Duration::from_secs(60)).negative_ttl(Duration::from_millis(100));
+    for _ in 0..2 {
+      assert!(resolve(&cache, "fails.test").await.is_err());
+      assert!(resolve(&cache, "empty.test").await.unwrap().is_empty());
+    }
+    assert_eq!(counting.lookups(), 2);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(resolve(&cache, "fails.test").await.is_err());
+    assert!(resolve(&cache, "empty.test").await.unwrap().is_empty());
+    assert_eq!(counting.lookups(), 4);
+
+    cache.clear();
+    cache.negative_ttl(Duration::ZERO);
+    for _ in 0..2 {
+      assert!(resolve(&cache, "fails.test").await.is_err());
+      assert!(resolve(&cache, "empty.test").await.unwrap().is_empty());
+    }
+    assert_eq!(counting.lookups(), 8);
+  }
+
+  #[tokio::test]
+  async fn the_host_expiring_soonest_is_evicted() {
+    let counting = Counting::default();
+    let mut cache = CachingResolver::with_resolver(counting.clone());
+    cache.max_entries(2);
+    for host in ["a.test", "b.test", "c.test"] {
+      resolve(&cache, host).await.unwrap();
+    }
+    assert_eq!(cache.cache.lock().unwrap().len(), 2);
+    resolve(&cache, "b.test").await.unwrap();
+    resolve(&cache, "c.test").await.unwrap();
+    assert_eq!(counting.lookups(), 3);
+    resolve(&cache, "a.test").await.unwrap();
+    assert_eq!(counting.lookups(), 4);
+
+    // An expired host makes room before the others
+    cache.clear();
+    cache.ttl(Duration::from_millis(50));
+    resolve(&cache, "a.test").await.unwrap();
+    cache.ttl(Duration::from_secs(60));
+    resolve(&cache, "b.test").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    resolve(&cache, "c.test").await.unwrap();
+    let cached = cache.cache.lock().unwrap();
+    assert!(cached.contains_key("b.test") && cached.contains_key("c.test"));
+  }
+
+  #[tokio::test]
+  async fn clones_share_the_cache() {
+    let counting = Counting::default();
+    let cache = CachingResolver::with_resolver(counting.clone());
+    let clone = cache.clone();
+    resolve(&cache, "www.example.com").await.unwrap();
+    resolve(&clone, "www.example.com").await.unwrap();
+    assert_eq!(counting.lookups(), 1);
+    clone.clear();
+    resolve(&cache, "www.example.com").await.unwrap();
+    assert_eq!(counting.lookups(), 2);
   }
-}
\ No newline at end of file
+
+  /// `IPv6 1, IPv4 1, IPv4 2, IPv6 2, IPv4 3`, the versions interleaved like a resolver may return them.
+  fn mixed() -> Vec<SocketAddr> {
+    ["[2001:db8::1]:0", "192.0.2.1:0", "192.0.2.2:0", "[2001:db8::2]:0", "192.0.2.3:0"].iter().map(|addr| addr.parse().unwrap()).collect()
+  }
+
+  fn picked(preference: AddressPreference, socket_addrs: Vec<SocketAddr>) -> Vec<usize> {
+    let all = mixed();
+    preference.apply(socket_addrs).iter().map(|addr| all.iter().position(|known| known == addr).unwrap() + 1).collect()
+  }
+
+  #[test]
+  fn address_preferences() {
+    assert_eq!(picked(AddressPreference::Resolver, mixed()), [1, 2, 3, 4, 5]);
+    assert_eq!(picked(AddressPreference::PreferIpv4, mixed()), [2, 3, 5, 1, 4]);
+    assert_eq!(picked(AddressPreference::PreferIpv6, mixed()), [1, 4, 2, 3, 5]);
+    assert_eq!(picked(AddressPreference::Ipv4Only, mixed()), [2, 3, 5]);
+    assert_eq!(picked(AddressPreference::Ipv6Only, mixed()), [1, 4]);
+
+    // The resolver's order decides which version goes first
+    let ipv4_first: Vec<_> = mixed().into_iter().skip(1).chain(mixed().into_iter().take(1)).collect();
+    assert_eq!(picked(AddressPreference::Resolver, ipv4_first.clone()), [2, 3, 4, 5, 1]);
+    assert_eq!(picked(AddressPreference::PreferIpv6, ipv4_first), [4, 1, 2, 3, 5]);
+
+    let ipv4_only: Vec<_> = mixed().into_iter().filter(SocketAddr::is_ipv4).collect();
+    assert_eq!(picked(AddressPreference::PreferIpv6, ipv4_only.clone()), [2, 3, 5]);
+    assert!(picked(AddressPreference::Ipv6Only, ipv4_only).is_empty());
+    assert!(picked(AddressPreference::PreferIpv4, vec![]).is_empty());
+  }
+
+  #[tokio::test]
+  async fn preferences_that_leave_no_address_fail() {
+    let ipv4_only = mixed().into_iter().filter(SocketAddr::is_ipv4).collect();
+    let overrides: HashMap<_, _> = vec![("www.example.com".to_string(), ipv4_only)].into_iter().collect();
+    let mut service = ResolverService::new(None, overrides.clone(), AddressPreference::Ipv6Only);
+    let error = tower::Service::call(&mut service, Name::from_str("www.example.com").unwrap()).await.unwrap_err();
+    assert_eq!(error.to_string(), "www.example.com has no addresses allowed by Ipv6Only");
+
+    let mut service = ResolverService::new(None, overrides, AddressPreference::PreferIpv6);
+    service.set_local_address(Some("::1".parse().unwrap()));
+    let error = tower::Service::call(&mut service, Name::from_str("www.example.com").unwrap()).await.unwrap_err();
+    assert_eq!(error.to_string(), "www.example.com has no addresses of the IP version of the local address ::1");
+  }
+}
@@ -1,66 +1,466 @@
-use crate::{decoder::Accepts, progress::Progress};
-use crate::dns::ResolverService;
-use std::io::Write;
-use hyper::client::Client;
+use crate::{decoder::{Accepts, Decoder}, progress::Progress};
+use crate::client::DownloadClient;
+use crate::checksum::{self, ExpectedDigest, HashedBody, Hasher};
+use crate::journal::Validator;
+use crate::proxy::Proxies;
+use crate::range::{ByteRange, ContentRange};
+use crate::retry::RetryPolicy;
+use crate::tls::TlsOptions;
+use crate::redirect::{self, RedirectChain, RedirectPolicy};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use hyper::body::HttpBody;
-use hyper::client::HttpConnector;
-use hyper_tls::HttpsConnector;
-use crate::dns::SocketAddrs;
+use crate::dns::{AddressPreference, SharedResolver};
+use http::{header, HeaderValue, StatusCode};
+use bytes::{Buf, Bytes};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
 use http::response::Parts;
-use crate::error::Error;
+use crate::error::{Error, Timeout};
+use std::time::Duration;
 
 type Request<T> = crate::http::Request<T>;
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-pub async fn download<T: HttpBody + Send + 'static>(request: Request<T>, to: &mut impl Write, https_only: bool, progress: &mut Option<Box<dyn Progress + Send>>, socket_addrs: Option<SocketAddrs>) -> Result<Parts, Error> where T::Data: Send, T::Error: Into<BoxError> {
-    let res;
+/// The settings of a `Downloader` that apply to the transfer itself.
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    /// If set to true, only HTTPS URLs will be used.
+    pub(crate) https_only: bool,
+    /// Resolves the hosts, the system resolver is used when unset.
+    pub(crate) resolver: Option<SharedResolver>,
+    /// The addresses to use for a host instead of resolving it.
+    pub(crate) dns_overrides: HashMap<String, Vec<SocketAddr>>,
+    /// Which IP versions are connected to, and which one is tried first.
+    pub(crate) address_preference: AddressPreference,
+    /// How long to wait before trying the other IP version, hyper's default is used when unset.
+    pub(crate) happy_eyeballs_timeout: Option<Option<Duration>>,
+    /// The local IP address the connections are made from.
+    pub(crate) local_address: Option<IpAddr>,
+    /// The network interface the connections are bound to.
+    pub(crate) device: Option<String>,
+    /// The root certificates and identity of the TLS connections.
+    pub(crate) tls: TlsOptions,
+    /// The range of bytes to request, if any.
+    pub(crate) range: Option<ByteRange>,
+    /// Decides whether failed downloads are attempted again.
+    pub(crate) retry: RetryPolicy,
+    /// The connect, read and total timeouts.
+    pub(crate) timeouts: Timeouts,
+    /// Decides which redirects are followed.
+    pub(crate) redirect: RedirectPolicy,
+    /// The shared client to download with, a new client is created for the download when unset.
+    pub(crate) client: Option<DownloadClient>,
+    /// The amount of segments to download in parallel when the server supports ranges.
+    pub(crate) segments: usize,
+    /// The digests the written bytes are expected to have.
+    pub(crate) digests: Vec<ExpectedDigest>,
+    /// If set to true, the digests the server sent for a response are verified.
+    pub(crate) verify_server_digests: bool,
+    /// The proxies the connections are made through.
+    pub(crate) proxies: Proxies,
+    /// The validators of the resource seen so far, to notice when it changes between responses.
+    pub(crate) validator: Option<Arc<Mutex<Validator>>>,
+}
 
-    if let Some(socket_addrs) = socket_addrs {    
-        //Connect tcp stream to a hostname:port
-        let resolver_service = ResolverService::new(socket_addrs.into());
-        let mut http_connector : HttpConnector<ResolverService> = HttpConnector::new_with_resolver(resolver_service);
-        http_connector.enforce_http(https_only);
-        let mut https_connector = HttpsConnector::new_with_connector(http_connector);
-        https_connector.https_only(https_only);
-        let client = Client::builder().build::<_, T>(https_connector);
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            https_only: true,
+            resolver: None,
+            dns_overrides: HashMap::new(),
+            address_preference: AddressPreference::default(),
+            happy_eyeballs_timeout: None,
+            local_address: None,
+            device: None,
+            tls: TlsOptions::default(),
+            range: None,
+            retry: RetryPolicy::never(),
+            timeouts: Timeouts::default(),
+            redirect: RedirectPolicy::default(),
+            client: None,
+            segments: 1,
+            digests: Vec::new(),
+            verify_server_digests: false,
+            proxies: Proxies::default(),
+            validator: None,
+        }
+    }
+}
 
-        // Send request
-        res = client.request(request).await.or_else(|e| Err(Error::HyperError(e.into())))?;
-    } else {
-        let mut https_connector = HttpsConnector::new();
-        https_connector.https_only(https_only);
-        let client = Client::builder().build::<_, T>(https_connector);
+impl Options {
+    /// The shared client, or a new client with the connection settings of these options.
+    pub(crate) fn client(&self) -> Result<DownloadClient, Error> {
+        match self.client.clone() {
+            Some(client) => Ok(client),
+            None => {
+                let mut builder = DownloadClient::builder();
+                builder.resolver = self.resolver.clone();
+                builder.dns_overrides = self.dns_overrides.clone();
+                builder.address_preference = self.address_preference;
+                builder.happy_eyeballs_timeout = self.happy_eyeballs_timeout;
+                builder.local_address = self.local_address;
+                builder.device = self.device.clone();
+                builder.tls = self.tls.clone();
+                if let Some(connect) = self.timeouts.connect {
+                    builder.connect_timeout(connect);
+                }
+                builder.proxies = self.proxies.clone();
+                builder.build()
+            }
+        }
+    }
+}
+
+/// The timeouts that apply to a download.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Timeouts {
+    /// The maximum time to establish a connection.
+    pub(crate) connect: Option<Duration>,
+    /// The maximum time to wait for the next chunk of the body.
+    pub(crate) read: Option<Duration>,
+    /// The maximum time for the whole download, including retries.
+    pub(crate) total: Option<Duration>,
+}
+
+/// The decoded body of a successful response, cut to the requested range.
+pub(crate) struct ResponseBody {
+    decoder: Decoder,
+    /// The amount of bytes to drop from the front of the body.
+    skip: u64,
+    /// The amount of bytes to keep after that, the rest of the body when unset.
+    remaining: Option<u64>,
+    /// The position in the resource the kept bytes start at.
+    pub(crate) position: u64,
+    /// The size of the resource, or the end of the requested range.
+    pub(crate) file_size: Option<u64>,
+    /// Hash the body as it's received, for the digests the server sent.
+    server_hashers: Arc<Mutex<Vec<Hasher>>>,
+}
+
+/// What earlier attempts of the same download have done.
+#[derive(Default)]
+struct Attempted {
+    /// The amount of bytes written to the writer.
+    written: u64,
+    /// The position the progress tracker has been brought to.
+    reported: u64,
+    /// Hash the written bytes for every expected digest.
+    hashers: Vec<Hasher>,
+}
 
-        // Send request
-        res = client.request(request).await.or_else(|e| Err(Error::HyperError(e.into())))?;
+pub async fn download<T: HttpBody + Send + 'static>(request: Request<T>, to: &mut (impl AsyncWrite + Unpin), progress: &mut Option<Box<dyn Progress + Send>>, mut options: Options) -> Result<Parts, Error> where T::Data: Send, T::Error: Into<BoxError> {
+    // The version of the resource is remembered, so retries only continue with bytes of the same version
+    options.validator.get_or_insert_with(Default::default);
+    let download = async {
+        // The body is buffered so it can be sent again when retrying.
+        let (head, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(|e| Error::InvalidBody(e.into()))?;
+        let request = Request::from_parts(head, body);
+
+        download_with_retries(&options.client()?, &request, to, progress, &options).await
+    };
+
+    match options.timeouts.total {
+        Some(total) => tokio::time::timeout(total, download).await.map_err(|_| Error::TimedOut(Timeout::Total))?,
+        None => download.await,
     }
+}
+
+async fn download_with_retries(client: &DownloadClient, request: &Request<Bytes>, to: &mut (impl AsyncWrite + Unpin), progress: &mut Option<Box<dyn Progress + Send>>, options: &Options) -> Result<Parts, Error> {
+    let mut attempted = Attempted {
+        hashers: options.digests.iter().cloned().map(Hasher::new).collect(),
+        ..Attempted::default()
+    };
+    let if_range = request.headers().contains_key(header::IF_RANGE);
+    let mut attempt = 1;
+    loop {
+        let mut next_request = copy_request(request);
+
+        // Continue after the bytes that earlier attempts already wrote
+        let range = match options.range {
+            range if attempted.written == 0 => range,
+            Some(range) => Some(ByteRange { start: range.start + attempted.written, end: range.end }),
+            None => Some(ByteRange { start: attempted.written, end: None }),
+        };
+        if let Some(range) = range {
+            // The written bytes are decoded, so the rest of the resource must not be encoded either
+            next_request.headers_mut().insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+            next_request.headers_mut().insert(header::RANGE, range.header_value());
+        }
+        if attempted.written > 0 && !if_range {
+            // Only the rest of the version the written bytes belong to may be sent
+            if let Some(value) = options.validator.as_ref().and_then(|validator| validator.lock().unwrap_or_else(PoisonError::into_inner).if_range()) {
+                next_request.headers_mut().insert(header::IF_RANGE, value);
+            }
+        }
+
+        match download_once(client, next_request, to, progress, range, range.is_some() && if_range, &mut attempted, options).await {
+            Err(error) if options.retry.should_retry(attempt, &error) => {
+                let delay = options.retry.delay(attempt);
+                log::warn!("Attempt {} to download {} failed with {:?}, retrying in {:?}", attempt, request.uri(), error, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            },
+            Err(error) => return Err(error),
+            Ok(parts) => {
+                to.flush().await?;
+                // The download only succeeds when the written bytes have the expected digests
+                for hasher in attempted.hashers {
+                    hasher.verify()?;
+                }
+                return Ok(parts);
+            },
+        }
+    }
+}
+
+/// Downloads `range` of the resource, `if_range` telling whether the request has an `If-Range` that a 200 response means the resource changed for.
+///
+/// The `If-Range` of a retry isn't one of those, as a server that doesn't support ranges answers it with the whole resource as well.
+/// The validators of the response are checked against those of the earlier responses instead.
+#[allow(clippy::too_many_arguments)]
+async fn download_once(client: &DownloadClient, request: Request<Bytes>, to: &mut (impl AsyncWrite + Unpin), progress: &mut Option<Box<dyn Progress + Send>>, range: Option<ByteRange>, if_range: bool, attempted: &mut Attempted, options: &Options) -> Result<Parts, Error> {
+    // Send request
+    let (res, redirects) = send_following_redirects(client, request, options).await?;
 
     let status = res.status();
     let (mut parts, body) = res.into_parts();
-    
-    if status == 200 || status == 206 {
+    parts.extensions.insert(redirects);
 
-        let mut decoder = crate::decoder::Decoder::detect(&mut parts.headers, crate::body::Body::from(body), Accepts::default());
-        if !decoder.is_encoded() && progress.is_some() {
-            if let Some(content_length) = parts.headers.get("content-length") {
-                let content_length : usize = content_length.to_str().expect("Couldn't convert content-length value to str.").parse().expect("Couldn't parse content-length as a usize.");
-                progress.as_deref_mut().map(|progress| progress.set_file_size(content_length)).unwrap().await;
+    // Resuming a download that was already complete
+    if let Some(length) = already_complete(&parts, range) {
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.set_file_size(length as usize).await;
+            move_progress(progress, &mut attempted.reported, length).await;
+        }
+        return Ok(parts);
+    }
+
+    if status == 200 || status == 206 {
+        check_unchanged(if_range, &parts, options)?;
+        let mut body = ResponseBody::new(&mut parts, body, range, options)?;
+        if let Some(progress) = progress.as_deref_mut() {
+            if let Some(file_size) = body.file_size {
+                progress.set_file_size(file_size as usize).await;
             }
+            move_progress(progress, &mut attempted.reported, body.position).await;
         }
-        while !decoder.is_end_stream() {
-            // todo: Add timeout for chunk
-            if let Some(chunk) = decoder.data().await {
-                let chunk = chunk?;
-                if progress.is_some() {
-                    progress.as_deref_mut().map(|progress| progress.add_to_progress(chunk.len())).unwrap().await;
+
+        while let Some(chunk) = body.next().await {
+            let (skipped, chunk) = chunk?;
+            if let Some(progress) = progress.as_deref_mut() {
+                // Skipped bytes have been downloaded as well
+                let downloaded = skipped + chunk.len() as u64;
+                progress.add_to_progress(downloaded as usize).await;
+                attempted.reported += downloaded;
+            }
+            if !chunk.is_empty() {
+                to.write_all(&chunk).await?;
+                attempted.written += chunk.len() as u64;
+                for hasher in attempted.hashers.iter_mut() {
+                    hasher.update(&chunk);
                 }
-                to.write_all(&chunk)?;
-            } else {
-                break;
             }
         }
+        body.finish()?;
         Ok::<Parts, Error>(parts)
     } else {
         Err::<Parts, Error>(Error::StatusError(status))
     }
-}
\ No newline at end of file
+}
+
+/// Fails with `Error::ResourceChanged` when a response is for another version of the resource than the earlier responses.
+pub(crate) fn check_unchanged(if_range: bool, parts: &Parts, options: &Options) -> Result<(), Error> {
+    // A server only ignores the Range of a request with If-Range when the resource changed
+    if if_range && parts.status == StatusCode::OK {
+        return Err(Error::ResourceChanged);
+    }
+    if let Some(validator) = &options.validator {
+        validator.lock().unwrap_or_else(PoisonError::into_inner).update(Validator::from_parts(parts))?;
+    }
+    Ok(())
+}
+
+/// The length of the resource when a request for the rest of it was answered with 416, because it was already complete.
+pub(crate) fn already_complete(parts: &Parts, range: Option<ByteRange>) -> Option<u64> {
+    let range = range.filter(|range| range.end.is_none())?;
+    if parts.status == StatusCode::RANGE_NOT_SATISFIABLE && ContentRange::unsatisfied_length(&parts.headers) == Some(range.start) {
+        Some(range.start)
+    } else {
+        None
+    }
+}
+
+impl ResponseBody {
+    /// Prepares reading the body of a 200 or 206 response to a request for `range`.
+    pub(crate) fn new(parts: &mut Parts, body: hyper::Body, range: Option<ByteRange>, options: &Options) -> Result<Self, Error> {
+        // The server digests cover the body before it's decoded, so they are hashed as the body is received
+        let server_digests = match options.verify_server_digests {
+            true => checksum::from_headers(&parts.headers, parts.status == StatusCode::PARTIAL_CONTENT),
+            false => Vec::new(),
+        };
+        let server_hashers = Arc::new(Mutex::new(server_digests.into_iter().map(Hasher::new).collect::<Vec<_>>()));
+        let body = crate::body::Body::with_read_timeout(HashedBody::new(body, server_hashers.clone()), options.timeouts.read);
+        let decoder = Decoder::detect(&mut parts.headers, body, Accepts::default());
+
+        let mut body = ResponseBody {
+            file_size: None,
+            position: 0,
+            skip: 0,
+            remaining: None,
+            server_hashers,
+            decoder,
+        };
+        if !body.decoder.is_encoded() {
+            body.file_size = parts.headers.get(header::CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        }
+
+        match range {
+            Some(range) if parts.status == StatusCode::PARTIAL_CONTENT => {
+                // The end of the range we received is the end of what we'll download.
+                body.file_size = Some(range.validate(&parts.headers)?.last + 1);
+                body.position = range.start;
+            },
+            Some(range) => {
+                // The server ignored our Range header and sent the whole resource, so cut the requested range out of it ourselves.
+                log::debug!("Server answered a range request with {}, falling back to skipping {} bytes", parts.status, range.start);
+                body.skip = range.start;
+                body.remaining = range.end.map(|end| end - range.start);
+                body.file_size = body.file_size.map(|length| range.end.map_or(length, |end| end.min(length)));
+            },
+            None => (),
+        }
+        Ok(body)
+    }
+
+    /// The next chunk within the requested range, along with the amount of bytes that were skipped before it.
+    pub(crate) async fn next(&mut self) -> Option<Result<(u64, Bytes), Error>> {
+        if self.decoder.is_end_stream() || self.remaining == Some(0) {
+            return None;
+        }
+        let mut chunk = match self.decoder.data().await? {
+            Ok(chunk) => chunk,
+            Err(error) => return Some(Err(error)),
+        };
+        let skipped = self.skip.min(chunk.len() as u64);
+        chunk.advance(skipped as usize);
+        self.skip -= skipped;
+        if let Some(remaining) = self.remaining.as_mut() {
+            chunk.truncate((*remaining).min(chunk.len() as u64) as usize);
+            *remaining -= chunk.len() as u64;
+        }
+        Some(Ok((skipped, chunk)))
+    }
+
+    /// Checks that the requested range was reached, and that the received body has the digests the server sent.
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        if self.skip > 0 {
+            return Err(Error::InvalidContentRange(format!("response ended {} bytes before the requested range", self.skip)));
+        }
+
+        let server_hashers = self.server_hashers.lock().map(|mut hashers| std::mem::take(&mut *hashers)).unwrap_or_default();
+        if !server_hashers.is_empty() {
+            if self.remaining == Some(0) && !self.decoder.is_end_stream() {
+                // The rest of the body was never received
+                log::debug!("Not verifying the server digests as the body wasn't read to the end");
+            } else {
+                for hasher in server_hashers {
+                    hasher.verify()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Asks for the first byte of the resource, to find out whether it can be downloaded in ranges.
+///
+/// Returns the response along with the length of the resource when it can be.
+pub(crate) async fn probe(client: &DownloadClient, request: &Request<Bytes>, options: &Options) -> Result<(Parts, Option<u64>), Error> {
+    let mut probe = copy_request(request);
+    probe.headers_mut().insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    probe.headers_mut().insert(header::RANGE, ByteRange { start: 0, end: Some(1) }.header_value());
+
+    let (res, redirects) = send_following_redirects(client, probe, options).await?;
+    let (mut parts, body) = res.into_parts();
+    if parts.status == StatusCode::OK || parts.status == StatusCode::PARTIAL_CONTENT {
+        check_unchanged(request.headers().contains_key(header::IF_RANGE), &parts, options)?;
+    }
+    // Read the single byte, so the connection can be reused
+    hyper::body::to_bytes(body).await?;
+    parts.extensions.insert(redirects);
+
+    // Ranges of an encoded body can't be decoded separately
+    let length = match ContentRange::from_headers(&parts.headers) {
+        Ok(Some(content_range)) if parts.status == StatusCode::PARTIAL_CONTENT && !parts.headers.contains_key(header::CONTENT_ENCODING) => content_range.complete_length,
+        _ => None,
+    };
+    Ok((parts, length))
+}
+
+/// Sends `request`, following the redirects allowed by the redirect policy.
+pub(crate) async fn send_following_redirects(client: &DownloadClient, mut request: Request<Bytes>, options: &Options) -> Result<(hyper::Response<hyper::Body>, RedirectChain), Error> {
+    let mut redirects = RedirectChain::new(request.uri().clone());
+    loop {
+        if options.https_only && request.uri().scheme() != Some(&http::uri::Scheme::HTTPS) {
+            return Err(Error::HttpsRequired(request.uri().clone()));
+        }
+        let res = client.request(copy_request(&request).map(hyper::Body::from)).await.map_err(request_error)?;
+        if !redirect::is_redirect(res.status()) || options.redirect.is_none() {
+            return Ok((res, redirects));
+        }
+
+        let next = redirect::location(res.headers(), request.uri())?;
+        options.redirect.check(redirects.redirects(), request.uri(), &next, options.https_only)?;
+        log::debug!("Following redirect {} from {} to {}", res.status(), request.uri(), next);
+
+        let (method, drop_body) = redirect::next_method(res.status(), request.method());
+        if drop_body {
+            *request.body_mut() = Bytes::new();
+            request.headers_mut().remove(header::CONTENT_TYPE);
+            request.headers_mut().remove(header::CONTENT_LENGTH);
+        }
+        let previous = std::mem::replace(request.uri_mut(), next.clone());
+        redirect::remove_sensitive_headers(request.headers_mut(), &previous, &next);
+        *request.method_mut() = method;
+        redirects.push(next);
+    }
+}
+
+/// Copies a buffered request, so it can be sent again.
+pub(crate) fn copy_request(request: &Request<Bytes>) -> Request<Bytes> {
+    let mut copy = Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+/// Turns a failed request into an `Error`, telling connect timeouts, proxy errors and pin mismatches apart from other connection errors.
+fn request_error(error: hyper::Error) -> Error {
+    let mut source = std::error::Error::source(&error);
+    while let Some(cause) = source {
+        if cause.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut) {
+            return Error::TimedOut(Timeout::Connect);
+        }
+        match cause.downcast_ref::<Error>() {
+            Some(Error::ProxyError(message)) => return Error::ProxyError(message.clone()),
+            Some(Error::PinMismatch { host, presented }) => return Error::PinMismatch { host: host.clone(), presented: presented.clone() },
+            _ => {}
+        }
+        source = cause.source();
+    }
+    Error::HyperError(error.into())
+}
+
+/// Moves the progress tracker from `reported` to `position`, going back when a download has to start over.
+async fn move_progress(progress: &mut (dyn Progress + Send), reported: &mut u64, position: u64) {
+    if *reported < position {
+        progress.add_to_progress((position - *reported) as usize).await;
+    } else if *reported > position {
+        progress.remove_from_progress((*reported - position) as usize).await;
+    }
+    *reported = position;
+}
@@ -3,7 +3,7 @@ use std::{error::Error as StdError, fmt::Display};
 #[derive(Debug)]
 pub enum Error {
     Decode(std::io::Error),
-    TimedOut(),
+    TimedOut(Timeout),
     InvalidBody(BoxError),
     NoneValue(String),
     InvalidHeaderValue(http::header::InvalidHeaderValue),
@@ -11,12 +11,59 @@ pub enum Error {
     IoError(std::io::Error),
     HyperError(BoxError),
     HttpError(BoxError),
+    InvalidContentRange(String),
+    EmptyRange(std::ops::Range<u64>),
+    TooManyRedirects(usize),
+    RedirectRejected(http::Uri),
+    InvalidRedirect(String),
+    HttpsRequired(http::Uri),
+    ChecksumMismatch { algorithm: crate::checksum::Algorithm, expected: String, actual: String },
+    ResourceChanged,
+    ProxyError(String),
+    TlsError(String),
+    PinMismatch { host: String, presented: String },
+    InvalidDigest(String),
 }
 
+/// The timeout that expired when an `Error::TimedOut` is returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    /// Connecting to the server took longer than the connect timeout.
+    Connect,
+    /// The server didn't send the next chunk of the body within the read timeout.
+    Read,
+    /// The whole download took longer than the total timeout.
+    Total,
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_str("")
+        match self {
+            Error::Decode(error) => write!(f, "couldn't decode the body: {}", error),
+            Error::TimedOut(Timeout::Connect) => f.write_str("connecting to the server timed out"),
+            Error::TimedOut(Timeout::Read) => f.write_str("reading the body timed out"),
+            Error::TimedOut(Timeout::Total) => f.write_str("the download timed out"),
+            Error::InvalidBody(error) => write!(f, "couldn't read the body: {}", error),
+            Error::NoneValue(message) if message.is_empty() => f.write_str("a value is missing"),
+            Error::NoneValue(message) => write!(f, "a value is missing: {}", message),
+            Error::InvalidHeaderValue(error) => write!(f, "invalid header value: {}", error),
+            Error::StatusError(status) => write!(f, "the server responded with {}", status),
+            Error::IoError(error) => write!(f, "I/O error: {}", error),
+            Error::HyperError(error) => write!(f, "HTTP error: {}", error),
+            Error::HttpError(error) => write!(f, "invalid request: {}", error),
+            Error::InvalidContentRange(message) => write!(f, "invalid Content-Range: {}", message),
+            Error::EmptyRange(range) => write!(f, "the range {}..{} is empty", range.start, range.end),
+            Error::TooManyRedirects(redirects) => write!(f, "stopped after {} redirects", redirects),
+            Error::RedirectRejected(uri) => write!(f, "the redirect to {} was rejected by the redirect policy", uri),
+            Error::InvalidRedirect(message) => write!(f, "invalid redirect: {}", message),
+            Error::HttpsRequired(uri) => write!(f, "{} doesn't use HTTPS", uri),
+            Error::ChecksumMismatch { algorithm, expected, actual } => write!(f, "expected the {:?} digest {}, but the download has {}", algorithm, expected, actual),
+            Error::ResourceChanged => f.write_str("the resource changed on the server during the download"),
+            Error::ProxyError(message) => write!(f, "proxy error: {}", message),
+            Error::TlsError(message) => write!(f, "TLS error: {}", message),
+            Error::PinMismatch { host, presented } => write!(f, "{} presented the public key {}, which isn't pinned for it", host, presented),
+            Error::InvalidDigest(message) => write!(f, "invalid expected digest: {}", message),
+        }
     }
 }
 
@@ -25,6 +72,15 @@ impl StdError for Error { }
 
 pub(crate) type BoxError = Box<dyn StdError + Send + Sync>;
 
+/// Turns an `Error` into an `io::Error` that still contains it, keeping the kind of timeouts and io errors.
+pub(crate) fn into_io(error: Error) -> std::io::Error {
+    match error {
+        Error::IoError(error) => error,
+        Error::TimedOut(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, error),
+        error => std::io::Error::other(error),
+    }
+}
+
 #[allow(unused)]
 pub(crate) fn decode_io(e: std::io::Error) -> Error {
     if e.get_ref().map(|r| r.is::<Error>()).unwrap_or(false) {
@@ -63,4 +119,27 @@ impl From<http::Error> for Error {
     fn from(error: http::Error) -> Self {
       Self::HttpError(error.into())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let uri: http::Uri = "http://example.com/file".parse().unwrap();
+        assert_eq!(Error::TimedOut(Timeout::Read).to_string(), "reading the body timed out");
+        assert_eq!(Error::StatusError(http::StatusCode::NOT_FOUND).to_string(), "the server responded with 404 Not Found");
+        assert_eq!(Error::EmptyRange(10..10).to_string(), "the range 10..10 is empty");
+        assert_eq!(Error::HttpsRequired(uri).to_string(), "http://example.com/file doesn't use HTTPS");
+        assert_eq!(Error::NoneValue(String::new()).to_string(), "a value is missing");
+        assert_eq!(Error::TlsError("bad certificate".to_string()).to_string(), "TLS error: bad certificate");
+        let mismatch = Error::ChecksumMismatch { algorithm: crate::checksum::Algorithm::Sha1, expected: "00".to_string(), actual: "ff".to_string() };
+        assert_eq!(mismatch.to_string(), "expected the Sha1 digest 00, but the download has ff");
+
+        // Wrapped errors are part of the message, also after a round trip through an io::Error
+        let error = into_io(Error::InvalidBody("connection reset".into()));
+        assert_eq!(error.to_string(), "couldn't read the body: connection reset");
+        assert_eq!(decode_io(error).to_string(), "couldn't read the body: connection reset");
+    }
+}
@@ -0,0 +1,278 @@
+use std::convert::Infallible;
+use std::ffi::OsString;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use async_trait::async_trait;
+use futures_util::future::{select, Either};
+use bytes::Bytes;
+use http::header;
+use http::response::Parts;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use crate::checksum::{ExpectedDigest, Hasher};
+use crate::download::{self, Options};
+use crate::error::Error;
+use crate::journal::{Journal, Validator};
+use crate::progress::Progress;
+use crate::range::{ByteRange, RangeSet};
+use crate::segment::Destination;
+
+type Request<T> = crate::http::Request<T>;
+
+/// How often the journal is saved while downloading.
+const JOURNAL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The `.part` file a download is written to, every segment through its own handle.
+struct PartFile<'a> {
+    path: &'a Path,
+    /// The position the download starts writing at.
+    origin: u64,
+}
+
+/// Writes a segment to the `.part` file without blocking, the file completing every write in the background.
+///
+/// A write is only finished, and added to the written ranges, once it completed, so the journal never claims bytes that may not be in the file.
+/// Until then the write is pending, and `write_all` passes the same bytes again.
+struct PartWriter<'a> {
+    file: File,
+    written: &'a Mutex<RangeSet>,
+    /// The position in the resource the next byte belongs at.
+    resource_position: u64,
+    /// The amount of bytes that are being written.
+    pending: Option<usize>,
+}
+
+/// Downloads the resource to `path` through a `.part` file, continuing where an earlier download left off.
+///
+/// When the resource changed since then, the download starts over.
+pub(crate) async fn download(request: Request<Bytes>, path: &Path, progress: &mut Option<Box<dyn Progress + Send>>, options: Options) -> Result<Parts, Error> {
+    match download_part(&request, path, progress, options.clone(), true).await {
+        Err(Error::ResourceChanged) => {
+            log::info!("{} changed since it was partially downloaded to {}, starting over", request.uri(), path.display());
+            download_part(&request, path, progress, options, false).await
+        },
+        result => result,
+    }
+}
+
+async fn download_part(request: &Request<Bytes>, path: &Path, progress: &mut Option<Box<dyn Progress + Send>>, mut options: Options, resume: bool) -> Result<Parts, Error> {
+    let part = part_path(path)?;
+    let journal_path = Journal::path(&part);
+    // Only a download of the whole resource is journaled, a range starts over every time
+    let journaled = options.range.is_none();
+    let mut journal = match journaled && resume {
+        true => load_journal(&journal_path, &part, request.uri().to_string()).await?,
+        false => Journal::new(request.uri().to_string()),
+    };
+    if journaled {
+        // The journal is written before the `.part` file, so there's never a `.part` file without one
+        journal.save(&journal_path).await?;
+    } else {
+        Journal::remove(&journal_path).await?;
+    }
+
+    let mut request = download::copy_request(request);
+    if !journal.completed.is_empty() {
+        // The server only sends the missing ranges when the resource didn't change since
+        if let Some(if_range) = journal.validator.if_range() {
+            request.headers_mut().insert(header::IF_RANGE, if_range);
+        }
+    }
+    let validator = Arc::new(Mutex::new(journal.validator.clone()));
+    options.validator = Some(validator.clone());
+    // The bytes of earlier runs are only part of the digest when the whole file is hashed
+    let digests = std::mem::take(&mut options.digests);
+
+    // Syncs the bytes the journal claims, while the download writes through handles of its own
+    let file = tokio::fs::OpenOptions::new().create(true).write(true).truncate(false).open(&part).await?;
+    let mut destination = PartFile { path: &part, origin: 0 };
+    if options.segments > 1 {
+        if journal.completed.is_empty() {
+            file.set_len(0).await?;
+        }
+    } else {
+        // A single stream continues after the first missing byte
+        let prefix = journal.completed.prefix_end();
+        journal.completed.truncate(prefix);
+        file.set_len(prefix).await?;
+        destination.origin = prefix;
+        if prefix > 0 {
+            log::debug!("Resuming the download to {} from {} bytes", path.display(), prefix);
+            options.range = Some(ByteRange { start: prefix, end: None });
+        }
+    }
+    let completed = Mutex::new(std::mem::take(&mut journal.completed));
+    let result = {
+        let downloading = pin!(crate::segment::download(request.map(hyper::Body::from), &destination, progress, options, &completed));
+        match journaled {
+            true => match select(downloading, pin!(journal_periodically(&journal.uri, &journal_path, &file, &completed, &validator))).await {
+                Either::Left((result, _)) => result,
+                Either::Right((never, _)) => match never {},
+            },
+            false => downloading.await,
+        }
+    };
+    journal.completed = completed.into_inner().unwrap_or_else(PoisonError::into_inner);
+    journal.validator = validator.lock().unwrap_or_else(PoisonError::into_inner).clone();
+
+    match result {
+        Ok(parts) => {
+            if let Err(error) = verify(&part, digests).await {
+                if let Error::ChecksumMismatch { .. } = error {
+                    drop(file);
+                    tokio::fs::remove_file(&part).await?;
+                    Journal::remove(&journal_path).await?;
+                }
+                return Err(error);
+            }
+            finalize(file, &part, path).await?;
+            Journal::remove(&journal_path).await?;
+            Ok(parts)
+        },
+        // The written bytes belong to another version of the resource
+        Err(Error::ResourceChanged) => Err(Error::ResourceChanged),
+        Err(error) => {
+            if journaled {
+                save_journal(&journal, &journal_path, &file).await?;
+            }
+            Err(error)
+        },
+    }
+}
+
+#[async_trait]
+impl Destination for PartFile<'_> {
+    async fn position(&self) -> Result<u64, Error> {
+        Ok(self.origin)
+    }
+
+    async fn writer<'a>(&'a self, position: u64, resource_position: u64, written: &'a Mutex<RangeSet>) -> Result<Box<dyn AsyncWrite + Unpin + Send + 'a>, Error> {
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(self.path).await?;
+        file.seek(SeekFrom::Start(position)).await?;
+        Ok(Box::new(PartWriter { file, written, resource_position, pending: None }))
+    }
+}
+
+impl AsyncWrite for PartWriter<'_> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending.is_none() {
+            this.pending = Some(futures_core::ready!(Pin::new(&mut this.file).poll_write(cx, buf))?);
+        }
+        let result = futures_core::ready!(Pin::new(&mut this.file).poll_flush(cx));
+        let written = this.pending.take().unwrap_or_default();
+        result?;
+        let range = this.resource_position..this.resource_position + written as u64;
+        this.written.lock().unwrap_or_else(PoisonError::into_inner).insert(range);
+        this.resource_position += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Saves the journal every `JOURNAL_INTERVAL` while the download runs, so it can be continued when the process is killed.
+///
+/// Never returns, as failing to save the journal only means that the download can't be continued.
+async fn journal_periodically(uri: &str, path: &Path, file: &File, completed: &Mutex<RangeSet>, validator: &Mutex<Validator>) -> Infallible {
+    let mut saved = None;
+    loop {
+        tokio::time::sleep(JOURNAL_INTERVAL).await;
+        let journal = Journal {
+            uri: uri.to_string(),
+            validator: validator.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+            completed: completed.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+        };
+        if saved.as_ref() == Some(&journal) {
+            continue;
+        }
+        match save_journal(&journal, path, file).await {
+            Ok(()) => saved = Some(journal),
+            Err(error) => log::warn!("Couldn't save the journal {}: {:?}", path.display(), error),
+        }
+    }
+}
+
+/// Saves `journal` once the bytes it claims are on disk.
+async fn save_journal(journal: &Journal, path: &Path, file: &File) -> Result<(), Error> {
+    file.sync_data().await?;
+    journal.save(path).await
+}
+
+/// The journal of an earlier download to `part`, limited to the bytes the `.part` file actually contains.
+///
+/// A `.part` file without a journal isn't continued, as there's no telling which of its bytes were written.
+async fn load_journal(journal_path: &Path, part: &Path, uri: String) -> Result<Journal, Error> {
+    let length = match tokio::fs::metadata(part).await {
+        Ok(metadata) => metadata.len(),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(error) => return Err(error.into()),
+    };
+    let mut journal = match Journal::load(journal_path).await? {
+        Some(journal) if journal.uri == uri => journal,
+        Some(journal) => {
+            log::debug!("Starting over, {} was partially downloaded from {} instead", part.display(), journal.uri);
+            Journal::new(uri)
+        },
+        None => {
+            if length > 0 {
+                log::debug!("Starting over, {} has no journal", part.display());
+            }
+            Journal::new(uri)
+        },
+    };
+    journal.completed.truncate(length);
+    Ok(journal)
+}
+
+/// The file a download to `path` is written to until it's complete, `path` with `.part` appended.
+pub(crate) fn part_path(path: &Path) -> Result<PathBuf, Error> {
+    let mut name = OsString::from(path.file_name().ok_or_else(|| Error::NoneValue(format!("{} has no file name", path.display())))?);
+    name.push(".part");
+    Ok(path.with_file_name(name))
+}
+
+/// Hashes the whole file for every expected digest, including the bytes written by earlier runs.
+pub(crate) async fn verify(path: &Path, digests: Vec<ExpectedDigest>) -> Result<(), Error> {
+    if digests.is_empty() {
+        return Ok(());
+    }
+    let mut hashers: Vec<Hasher> = digests.into_iter().map(Hasher::new).collect();
+    let mut file = File::open(path).await?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buffer[..read]);
+        }
+    }
+    hashers.into_iter().try_for_each(Hasher::verify)
+}
+
+/// Makes sure the completed `part` file is on disk, before moving it to `path`.
+pub(crate) async fn finalize(file: File, part: &Path, path: &Path) -> Result<(), Error> {
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(part, path).await?;
+    // The rename itself is only durable once the directory is synced
+    #[cfg(unix)]
+    {
+        if let Some(directory) = path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+            File::open(directory).await?.sync_all().await?;
+        }
+    }
+    Ok(())
+}
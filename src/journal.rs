@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+use http::{HeaderValue, StatusCode, header};
+use http::response::Parts;
+use tokio::io::AsyncWriteExt;
+use crate::error::Error;
+use crate::range::{ContentRange, RangeSet};
+
+/// The first line of a journal, to recognize the format.
+const HEADER: &str = "download-async journal 1";
+
+/// What an interrupted download to a `.part` file has done, stored next to it to continue after a restart.
+///
+/// The journal is a text file with a `key value` pair on every line:
+///
+/// ```text
+/// download-async journal 1
+/// uri https://www.example.com/file.zip
+/// etag "5d8c72a5"
+/// last-modified Tue, 15 Nov 1994 12:45:26 GMT
+/// length 1048576
+/// range 0 524288
+/// range 786432 1048576
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Journal {
+    /// The URI that was downloaded.
+    pub(crate) uri: String,
+    /// The version of the resource the written bytes belong to.
+    pub(crate) validator: Validator,
+    /// The ranges of the resource that have been written to the `.part` file, `end` being exclusive.
+    pub(crate) completed: RangeSet,
+}
+
+/// Identifies the version of a resource, to notice when it changes between responses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Validator {
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+    /// The length of the whole resource.
+    pub(crate) length: Option<u64>,
+}
+
+impl Journal {
+    pub(crate) fn new(uri: String) -> Self {
+        Self {
+            uri,
+            validator: Validator::default(),
+            completed: RangeSet::default(),
+        }
+    }
+
+    /// The journal of the download to `part`.
+    pub(crate) fn path(part: &Path) -> PathBuf {
+        let mut path = part.as_os_str().to_owned();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    /// Reads the journal at `path`, `None` when there is none or it can't be parsed.
+    pub(crate) async fn load(path: &Path) -> Result<Option<Self>, Error> {
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let journal = Self::parse(&contents);
+        if journal.is_none() {
+            log::warn!("Ignoring the journal {} that couldn't be parsed", path.display());
+        }
+        Ok(journal)
+    }
+
+    /// Parses a journal, rejecting one that was cut off or whose ranges don't fit the resource.
+    fn parse(contents: &str) -> Option<Self> {
+        // Every line ends with a newline, so a missing one means the last line was cut off
+        let mut lines = contents.strip_suffix('\n')?.split('\n');
+        if lines.next()? != HEADER {
+            return None;
+        }
+        let mut journal = Self::new(String::new());
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "uri" => journal.uri = value.to_string(),
+                "etag" => journal.validator.etag = Some(value.to_string()),
+                "last-modified" => journal.validator.last_modified = Some(value.to_string()),
+                "length" => journal.validator.length = Some(value.parse().ok()?),
+                "range" => {
+                    let (start, end): (u64, u64) = value.split_once(' ').and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))?;
+                    if start >= end {
+                        return None;
+                    }
+                    journal.completed.insert(start..end);
+                },
+                _ => return None,
+            }
+        }
+        let beyond_length = |length| journal.completed.iter().any(|range| range.end > length);
+        if journal.uri.is_empty() || journal.validator.length.is_some_and(beyond_length) {
+            return None;
+        }
+        Some(journal)
+    }
+
+    /// Replaces the journal at `path`, so that it's either the earlier or this journal after a crash.
+    pub(crate) async fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut contents = format!("{}\nuri {}\n", HEADER, self.uri);
+        if let Some(etag) = &self.validator.etag {
+            contents.push_str(&format!("etag {}\n", etag));
+        }
+        if let Some(last_modified) = &self.validator.last_modified {
+            contents.push_str(&format!("last-modified {}\n", last_modified));
+        }
+        if let Some(length) = self.validator.length {
+            contents.push_str(&format!("length {}\n", length));
+        }
+        for range in self.completed.iter() {
+            contents.push_str(&format!("range {} {}\n", range.start, range.end));
+        }
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+
+    /// Removes the journal at `path`, if there is one.
+    pub(crate) async fn remove(path: &Path) -> Result<(), Error> {
+        match tokio::fs::remove_file(path).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validator {
+    /// The validators of the resource a response was sent for.
+    pub(crate) fn from_parts(parts: &Parts) -> Self {
+        let text = |name| parts.headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok()).map(str::to_string);
+        let length = match parts.status {
+            StatusCode::PARTIAL_CONTENT => ContentRange::from_headers(&parts.headers).ok().flatten().and_then(|range| range.complete_length),
+            // The length of an encoded body isn't the length of the resource
+            _ if parts.headers.contains_key(header::CONTENT_ENCODING) => None,
+            _ => parts.headers.get(header::CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse().ok()),
+        };
+        Self {
+            etag: text(header::ETAG),
+            last_modified: text(header::LAST_MODIFIED),
+            length,
+        }
+    }
+
+    /// The value of the `If-Range` header that only lets the server send a range of this version of the resource.
+    ///
+    /// Weak ETags can't be used for ranges, in which case `Last-Modified` is used.
+    pub(crate) fn if_range(&self) -> Option<HeaderValue> {
+        let value = self.strong_etag().or(self.last_modified.as_deref())?;
+        HeaderValue::from_str(value).ok()
+    }
+
+    /// Remembers the validators of `other`, failing with `Error::ResourceChanged` when they contradict the known ones.
+    pub(crate) fn update(&mut self, other: Validator) -> Result<(), Error> {
+        let changed = matches!((self.strong_etag(), other.strong_etag()), (Some(known), Some(etag)) if known != etag)
+            || matches!((&self.last_modified, &other.last_modified), (Some(known), Some(last_modified)) if known != last_modified)
+            || matches!((self.length, other.length), (Some(known), Some(length)) if known != length);
+        if changed {
+            return Err(Error::ResourceChanged);
+        }
+        if self.strong_etag().is_none() && other.etag.is_some() {
+            self.etag = other.etag;
+        }
+        self.last_modified = self.last_modified.take().or(other.last_modified);
+        self.length = self.length.or(other.length);
+        Ok(())
+    }
+
+    fn strong_etag(&self) -> Option<&str> {
+        self.etag.as_deref().filter(|etag| etag.starts_with('"'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal() -> Journal {
+        let mut journal = Journal::new("https://www.example.com/file.zip".to_string());
+        journal.validator = Validator {
+            etag: Some("\"5d8c72a5\"".to_string()),
+            last_modified: Some("Tue, 15 Nov 1994 12:45:26 GMT".to_string()),
+            length: Some(1048576),
+        };
+        journal.completed.insert(0..524288);
+        journal.completed.insert(786432..1048576);
+        journal
+    }
+
+    const TEXT: &str = "download-async journal 1\nuri https://www.example.com/file.zip\netag \"5d8c72a5\"\n\
+        last-modified Tue, 15 Nov 1994 12:45:26 GMT\nlength 1048576\nrange 0 524288\nrange 786432 1048576\n";
+
+    #[tokio::test]
+    async fn save_and_load() {
+        let directory = std::env::temp_dir().join(format!("download-async-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = Journal::path(&directory.join("file.zip.part"));
+        assert_eq!(Journal::load(&path).await.unwrap(), None);
+
+        journal().save(&path).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), TEXT);
+        assert_eq!(Journal::load(&path).await.unwrap(), Some(journal()));
+
+        let empty = Journal::new("https://www.example.com/other".to_string());
+        empty.save(&path).await.unwrap();
+        assert_eq!(Journal::load(&path).await.unwrap(), Some(empty));
+
+        Journal::remove(&path).await.unwrap();
+        Journal::remove(&path).await.unwrap();
+        assert_eq!(Journal::load(&path).await.unwrap(), None);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Journal::parse(TEXT), Some(journal()));
+        // Overlapping and adjacent ranges are merged, and empty lines are ignored
+        let merged = Journal::parse("download-async journal 1\nuri https://www.example.com/file.zip\n\nrange 0 10\nrange 5 20\nrange 20 30\n").unwrap();
+        assert_eq!(merged.completed.iter().collect::<Vec<_>>(), vec![&(0..30)]);
+        assert_eq!(merged.validator, Validator::default());
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        assert_eq!(Journal::parse(&TEXT.replace("journal 1", "journal 2")), None);
+        assert_eq!(Journal::parse(&TEXT.replace("download-async journal 1\n", "")), None);
+        assert_eq!(Journal::parse(""), None);
+    }
+
+    #[test]
+    fn truncated_journals_are_rejected() {
+        // Cut off within the last line, and within the line of every key
+        assert_eq!(Journal::parse(&TEXT[..TEXT.len() - 3]), None);
+        for line in ["uri", "etag", "length 10x", "range 0", "range 0 ", "range 0 1x", "range", "unknown 1"] {
+            let text = format!("download-async journal 1\nuri https://www.example.com/file.zip\n{}\n", line);
+            assert_eq!(Journal::parse(&text), None, "{:?} was accepted", line);
+        }
+        assert_eq!(Journal::parse("download-async journal 1\nrange 0 10\n"), None);
+    }
+
+    #[test]
+    fn mismatched_lengths_are_rejected() {
+        let with = |lines: &str| format!("download-async journal 1\nuri https://www.example.com/file.zip\n{}", lines);
+        assert_eq!(Journal::parse(&with("range 10 10\n")), None);
+        assert_eq!(Journal::parse(&with("range 10 5\n")), None);
+        assert_eq!(Journal::parse(&with("length 100\nrange 50 101\n")), None);
+        assert_eq!(Journal::parse(&with("range 50 101\nlength 100\n")), None);
+        assert!(Journal::parse(&with("length 100\nrange 50 100\n")).is_some());
+    }
+}
@@ -5,10 +5,31 @@ mod dns;
 mod builder;
 mod body;
 mod decoder;
+mod range;
+mod retry;
+mod redirect;
+mod client;
+mod segment;
+mod checksum;
+mod writer;
+mod stream;
+mod file;
+mod journal;
+mod proxy;
+mod tcp;
+mod tls;
 
 pub use http;
 pub use builder::Downloader;
-pub use error::Error;
-pub use dns::SocketAddrs;
+pub use client::{DownloadClient, DownloadClientBuilder};
+pub use checksum::Algorithm;
+pub use stream::{DownloadReader, DownloadStream};
+pub use proxy::Proxy;
+pub use tls::{Certificate, Identity};
+pub use bytes::Bytes;
+pub use error::{Error, Timeout};
+pub use dns::{AddressPreference, CachingResolver, Name, Resolve, Resolving, SocketAddrs};
 pub use hyper::body::Body;
-pub use progress::Progress;
\ No newline at end of file
+pub use progress::Progress;
+pub use retry::RetryPolicy;
+pub use redirect::{RedirectChain, RedirectPolicy};
\ No newline at end of file
@@ -0,0 +1,251 @@
+use std::ops::Range;
+use http::{HeaderMap, HeaderValue, header};
+use crate::error::Error;
+
+/// A range of bytes to request from the server, `end` being exclusive like a Rust `Range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: Option<u64>,
+}
+
+/// A parsed `Content-Range` header, `last` being inclusive like on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ContentRange {
+    pub(crate) first: u64,
+    pub(crate) last: u64,
+    pub(crate) complete_length: Option<u64>,
+}
+
+impl ByteRange {
+    /// The value of the `Range` header for this range.
+    pub(crate) fn header_value(&self) -> HeaderValue {
+        let value = match self.end {
+            Some(end) => format!("bytes={}-{}", self.start, end.saturating_sub(1)),
+            None => format!("bytes={}-", self.start),
+        };
+        HeaderValue::from_str(&value).expect("A formatted byte range is always a valid header value")
+    }
+
+    /// Checks that the `Content-Range` of a `206 Partial Content` response covers what was asked for.
+    pub(crate) fn validate(&self, headers: &HeaderMap) -> Result<ContentRange, Error> {
+        let content_range = ContentRange::from_headers(headers)?
+            .ok_or_else(|| Error::InvalidContentRange("206 response without a Content-Range header".to_string()))?;
+        if content_range.first != self.start {
+            return Err(Error::InvalidContentRange(format!("requested bytes from {}, but received bytes from {}", self.start, content_range.first)));
+        }
+        let expected_last = match (self.end, content_range.complete_length) {
+            (Some(end), Some(length)) => Some(end.min(length).saturating_sub(1)),
+            (Some(end), None) => Some(end.saturating_sub(1)),
+            (None, Some(length)) => Some(length.saturating_sub(1)),
+            (None, None) => None,
+        };
+        if let Some(expected_last) = expected_last {
+            if content_range.last != expected_last {
+                return Err(Error::InvalidContentRange(format!("expected bytes up to {}, but received bytes up to {}", expected_last, content_range.last)));
+            }
+        }
+        Ok(content_range)
+    }
+}
+
+impl ContentRange {
+    /// Parses a `bytes first-last/length` or `bytes first-last/*` Content-Range header, if there is one.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, Error> {
+        let value = match headers.get(header::CONTENT_RANGE) {
+            Some(value) => value.to_str().map_err(|_| Error::InvalidContentRange("Content-Range is not valid ASCII".to_string()))?,
+            None => return Ok(None),
+        };
+        let invalid = || Error::InvalidContentRange(format!("couldn't parse Content-Range `{}`", value));
+        let range = value.trim().strip_prefix("bytes ").ok_or_else(invalid)?;
+        let (span, length) = range.split_once('/').ok_or_else(invalid)?;
+        let complete_length = match length {
+            "*" => None,
+            length => Some(length.parse::<u64>().map_err(|_| invalid())?),
+        };
+        let (first, last) = span.split_once('-').ok_or_else(invalid)?;
+        let first = first.parse::<u64>().map_err(|_| invalid())?;
+        let last = last.parse::<u64>().map_err(|_| invalid())?;
+        if last < first || complete_length.is_some_and(|length| last >= length) {
+            return Err(invalid());
+        }
+        Ok(Some(ContentRange { first, last, complete_length }))
+    }
+
+    /// Parses the `bytes */length` Content-Range sent along with `416 Range Not Satisfiable`.
+    pub(crate) fn unsatisfied_length(headers: &HeaderMap) -> Option<u64> {
+        headers.get(header::CONTENT_RANGE)?
+            .to_str().ok()?
+            .trim()
+            .strip_prefix("bytes */")?
+            .parse().ok()
+    }
+}
+
+/// A set of byte ranges, kept sorted and merged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RangeSet {
+    ranges: Vec<Range<u64>>,
+}
+
+impl RangeSet {
+    /// Adds `range` to the set, merging it with the ranges it overlaps or touches.
+    pub(crate) fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let mut merged = range;
+        self.ranges.retain(|range| {
+            if range.end < merged.start || range.start > merged.end {
+                true
+            } else {
+                merged = merged.start.min(range.start)..merged.end.max(range.end);
+                false
+            }
+        });
+        let index = self.ranges.partition_point(|range| range.start < merged.start);
+        self.ranges.insert(index, merged);
+    }
+
+    /// The parts of `within` that aren't in the set.
+    pub(crate) fn missing(&self, within: Range<u64>) -> Vec<Range<u64>> {
+        let mut missing = Vec::new();
+        let mut position = within.start;
+        for range in self.ranges.iter().filter(|range| range.end > within.start && range.start < within.end) {
+            if range.start > position {
+                missing.push(position..range.start);
+            }
+            position = position.max(range.end);
+        }
+        if position < within.end {
+            missing.push(position..within.end);
+        }
+        missing
+    }
+
+    /// The amount of bytes of `within` that are in the set.
+    pub(crate) fn covered(&self, within: Range<u64>) -> u64 {
+        let missing: u64 = self.missing(within.clone()).iter().map(|range| range.end - range.start).sum();
+        within.end.saturating_sub(within.start) - missing
+    }
+
+    /// The end of the range that starts at 0, or 0 when the first byte is missing.
+    pub(crate) fn prefix_end(&self) -> u64 {
+        self.ranges.first().filter(|range| range.start == 0).map_or(0, |range| range.end)
+    }
+
+    /// Removes everything from `end` onwards.
+    pub(crate) fn truncate(&mut self, end: u64) {
+        self.ranges.retain(|range| range.start < end);
+        if let Some(last) = self.ranges.last_mut() {
+            last.end = last.end.min(end);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Range<u64>> {
+        self.ranges.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(content_range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(content_range).unwrap());
+        headers
+    }
+
+    #[test]
+    fn header_value() {
+        assert_eq!(ByteRange { start: 0, end: Some(10) }.header_value(), "bytes=0-9");
+        assert_eq!(ByteRange { start: 42, end: None }.header_value(), "bytes=42-");
+    }
+
+    #[test]
+    fn content_range_from_headers() {
+        assert_eq!(ContentRange::from_headers(&headers("bytes 0-9/100")).unwrap(), Some(ContentRange { first: 0, last: 9, complete_length: Some(100) }));
+        assert_eq!(ContentRange::from_headers(&headers(" bytes 5-5/* ")).unwrap(), Some(ContentRange { first: 5, last: 5, complete_length: None }));
+        assert_eq!(ContentRange::from_headers(&HeaderMap::new()).unwrap(), None);
+        for invalid in ["bytes 9-0/100", "bytes 0-100/100", "bytes */100", "items 0-9/100", "bytes 0-9", "bytes 0-x/100", "bytes -9/100"] {
+            assert!(matches!(ContentRange::from_headers(&headers(invalid)), Err(Error::InvalidContentRange(_))), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn unsatisfied_length() {
+        assert_eq!(ContentRange::unsatisfied_length(&headers("bytes */100")), Some(100));
+        assert_eq!(ContentRange::unsatisfied_length(&headers("bytes 0-9/100")), None);
+        assert_eq!(ContentRange::unsatisfied_length(&headers("bytes */*")), None);
+        assert_eq!(ContentRange::unsatisfied_length(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn validate() {
+        let range = ByteRange { start: 10, end: Some(20) };
+        assert!(range.validate(&headers("bytes 10-19/100")).is_ok());
+        assert!(range.validate(&headers("bytes 10-19/*")).is_ok());
+        // The range is cut off at the end of the resource
+        assert!(range.validate(&headers("bytes 10-14/15")).is_ok());
+        assert!(range.validate(&headers("bytes 11-19/100")).is_err());
+        assert!(range.validate(&headers("bytes 10-18/100")).is_err());
+        assert!(range.validate(&headers("bytes 10-29/100")).is_err());
+        assert!(range.validate(&HeaderMap::new()).is_err());
+
+        let open = ByteRange { start: 10, end: None };
+        assert!(open.validate(&headers("bytes 10-99/100")).is_ok());
+        assert!(open.validate(&headers("bytes 10-49/*")).is_ok());
+        assert!(open.validate(&headers("bytes 10-49/100")).is_err());
+    }
+
+    #[test]
+    fn range_set_insert_merges() {
+        let mut set = RangeSet::default();
+        set.insert(10..20);
+        set.insert(30..40);
+        set.insert(0..0);
+        assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![10..20, 30..40]);
+        // Touching ranges are merged as well as overlapping ones
+        set.insert(20..25);
+        set.insert(5..12);
+        assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![5..25, 30..40]);
+        set.insert(0..100);
+        assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![0..100]);
+    }
+
+    #[test]
+    fn range_set_missing_and_covered() {
+        let mut set = RangeSet::default();
+        assert_eq!(set.missing(0..10), vec![0..10]);
+        set.insert(10..20);
+        set.insert(30..40);
+        assert_eq!(set.missing(0..50), vec![0..10, 20..30, 40..50]);
+        assert_eq!(set.missing(15..35), vec![20..30]);
+        assert_eq!(set.missing(10..20), Vec::<Range<u64>>::new());
+        assert_eq!(set.covered(0..50), 20);
+        assert_eq!(set.covered(15..35), 10);
+        assert_eq!(set.covered(20..30), 0);
+    }
+
+    #[test]
+    fn range_set_prefix_and_truncate() {
+        let mut set = RangeSet::default();
+        assert_eq!(set.prefix_end(), 0);
+        set.insert(10..20);
+        assert_eq!(set.prefix_end(), 0);
+        set.insert(0..5);
+        set.insert(30..40);
+        assert_eq!(set.prefix_end(), 5);
+        set.truncate(35);
+        assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![0..5, 10..20, 30..35]);
+        set.truncate(20);
+        assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![0..5, 10..20]);
+        set.truncate(0);
+        assert!(set.is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+use http::{HeaderMap, Method, StatusCode, Uri, header};
+use crate::error::Error;
+
+/// Decides which redirects are followed.
+///
+/// By default up to 10 redirects are followed to any origin.
+/// Redirects from https to http are always rejected when only https is allowed,
+/// and the `Authorization` and `Cookie` headers are never sent to a different host.
+///
+/// # Examples
+///
+/// ```
+/// let mut policy = download_async::RedirectPolicy::default();
+/// policy.limit(3).same_origin_only(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RedirectPolicy {
+  /// The maximum amount of redirects to follow.
+  limit: usize,
+  /// If set to true, redirects to a different scheme, host or port are rejected.
+  same_origin_only: bool,
+  /// Decides whether a redirect from the first to the second URI is followed.
+  filter: Option<fn(&Uri, &Uri) -> bool>
+}
+
+/// The URIs that were requested while following redirects, stored in the extensions of the returned `Parts`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate tokio;
+/// extern crate download_async;
+///
+/// #[tokio::main]
+/// async fn main() {
+///   let uri = download_async::http::Uri::from_static("https://www.example.com");
+///   let mut downloader = download_async::Downloader::new();
+///   downloader.use_uri(uri);
+///   let mut buffer = vec![];
+///   if let Ok(parts) = downloader.download(download_async::Body::empty(), &mut buffer).await {
+///     let chain = parts.extensions.get::<download_async::RedirectChain>().expect("Always set on success");
+///     println!("Downloaded from {}", chain.final_uri());
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectChain {
+  uris: Vec<Uri>
+}
+
+impl Default for RedirectPolicy {
+  fn default() -> Self {
+    Self {
+      limit: 10,
+      same_origin_only: false,
+      filter: None
+    }
+  }
+}
+
+impl RedirectPolicy {
+  /// Creates a `RedirectPolicy` that doesn't follow any redirect.
+  pub fn none() -> Self {
+    let mut policy = Self::default();
+    policy.limit(0);
+    policy
+  }
+
+  /// Sets the maximum amount of redirects to follow, after which `Error::TooManyRedirects` is returned.
+  pub fn limit(&mut self, limit: usize) -> &mut Self {
+    self.limit = limit;
+    self
+  }
+
+  /// Only follows redirects to the same scheme, host and port.
+  pub fn same_origin_only(&mut self, same_origin_only: bool) -> &mut Self {
+    self.same_origin_only = same_origin_only;
+    self
+  }
+
+  /// Only follows a redirect when `filter` returns true for the current and the next URI.
+  pub fn filter(&mut self, filter: fn(&Uri, &Uri) -> bool) -> &mut Self {
+    self.filter = Some(filter);
+    self
+  }
+
+  /// Whether this policy never follows a redirect, in which case redirects are returned as `Error::StatusError`.
+  pub(crate) fn is_none(&self) -> bool {
+    self.limit == 0
+  }
+
+  /// Checks whether the redirect from `from` to `to` may be followed after `followed` earlier redirects.
+  pub(crate) fn check(&self, followed: usize, from: &Uri, to: &Uri, https_only: bool) -> Result<(), Error> {
+    if followed >= self.limit {
+      return Err(Error::TooManyRedirects(followed));
+    }
+    let downgrade = https_only && to.scheme() != Some(&http::uri::Scheme::HTTPS);
+    let cross_origin = self.same_origin_only && !same_origin(from, to);
+    let filtered = self.filter.is_some_and(|filter| !filter(from, to));
+    if downgrade || cross_origin || filtered {
+      return Err(Error::RedirectRejected(to.clone()));
+    }
+    Ok(())
+  }
+}
+
+impl RedirectChain {
+  pub(crate) fn new(uri: Uri) -> Self {
+    Self {
+      uris: vec![uri]
+    }
+  }
+
+  pub(crate) fn push(&mut self, uri: Uri) {
+    self.uris.push(uri);
+  }
+
+  /// The URI the response was received from.
+  pub fn final_uri(&self) -> &Uri {
+    self.uris.last().expect("A chain always contains the requested URI")
+  }
+
+  /// Every requested URI in order, starting with the original one.
+  pub fn uris(&self) -> &[Uri] {
+    &self.uris
+  }
+
+  /// The amount of redirects that were followed.
+  pub fn redirects(&self) -> usize {
+    self.uris.len() - 1
+  }
+}
+
+/// Whether `status` is a redirect that can be followed.
+pub(crate) fn is_redirect(status: StatusCode) -> bool {
+  matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER | StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT)
+}
+
+/// The method to use after a redirect with `status`, and whether the request body is dropped.
+pub(crate) fn next_method(status: StatusCode, method: &Method) -> (Method, bool) {
+  match status {
+    StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => (method.clone(), false),
+    _ if method == Method::HEAD => (Method::HEAD, false),
+    _ if status == StatusCode::SEE_OTHER || method == Method::POST => (Method::GET, true),
+    _ => (method.clone(), false)
+  }
+}
+
+/// Resolves the `Location` header of a redirect response against the URI that was requested, as RFC 3986 section 5.2 describes.
+///
+/// The fragment of the location is dropped, it's never sent to the server.
+pub(crate) fn location(headers: &HeaderMap, base: &Uri) -> Result<Uri, Error> {
+  let location = headers.get(header::LOCATION)
+    .ok_or_else(|| Error::InvalidRedirect("redirect without a Location header".to_string()))?
+    .to_str()
+    .map_err(|_| Error::InvalidRedirect("Location is not valid ASCII".to_string()))?;
+  let invalid = || Error::InvalidRedirect(format!("couldn't resolve Location `{}`", location));
+
+  let reference = Reference::parse(location);
+  let (scheme, authority, path, query) = match reference {
+    Reference { scheme: Some(scheme), authority, path, query } => (scheme, authority.ok_or_else(invalid)?, remove_dot_segments(path), query),
+    Reference { authority: Some(authority), path, query, .. } => (base.scheme_str().ok_or_else(invalid)?, authority, remove_dot_segments(path), query),
+    reference => {
+      let path = match reference.path {
+        "" => base.path().to_string(),
+        path if path.starts_with('/') => remove_dot_segments(path),
+        // A path relative to the directory of the requested path
+        path => remove_dot_segments(&format!("{}/{}", base.path().rsplit_once('/').map_or("", |(directory, _)| directory), path))
+      };
+      let query = match (reference.path, reference.query) {
+        ("", None) => base.query(),
+        (_, query) => query
+      };
+      (base.scheme_str().ok_or_else(invalid)?, base.authority().ok_or_else(invalid)?.as_str(), path, query)
+    }
+  };
+  let path = if path.is_empty() { "/" } else { path.as_str() };
+  let query = query.map(|query| format!("?{}", query)).unwrap_or_default();
+  format!("{}://{}{}{}", scheme, authority, path, query).parse().map_err(|_| invalid())
+}
+
+/// The components of a URI reference, RFC 3986 section 4.1, without its fragment.
+struct Reference<'a> {
+  scheme: Option<&'a str>,
+  authority: Option<&'a str>,
+  path: &'a str,
+  query: Option<&'a str>
+}
+
+impl<'a> Reference<'a> {
+  fn parse(reference: &'a str) -> Self {
+    let reference = reference.split('#').next().unwrap_or_default();
+    let (reference, query) = match reference.split_once('?') {
+      Some((reference, query)) => (reference, Some(query)),
+      None => (reference, None)
+    };
+    let is_scheme = |scheme: &str| scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+      && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+    let (scheme, rest) = match reference.split_once(':') {
+      Some((scheme, rest)) if is_scheme(scheme) => (Some(scheme), rest),
+      _ => (None, reference)
+    };
+    let (authority, path) = match rest.strip_prefix("//") {
+      Some(rest) => {
+        let end = rest.find('/').unwrap_or(rest.len());
+        (Some(&rest[..end]), &rest[end..])
+      },
+      None => (None, rest)
+    };
+    Self { scheme, authority, path, query }
+  }
+}
+
+/// Removes the `.` and `..` segments of `path`, RFC 3986 section 5.2.4.
+fn remove_dot_segments(path: &str) -> String {
+  let (root, path) = match path.strip_prefix('/') {
+    Some(path) => ("/", path),
+    None => ("", path)
+  };
+  let segments: Vec<&str> = path.split('/').collect();
+  let mut output = Vec::with_capacity(segments.len());
+  for (index, segment) in segments.iter().enumerate() {
+    match *segment {
+      "." | ".." => {
+        if *segment == ".." {
+          output.pop();
+        }
+        // A path ending in a dot segment still ends in a directory
+        if index == segments.len() - 1 {
+          output.push("");
+        }
+      },
+      segment => output.push(segment)
+    }
+  }
+  format!("{}{}", root, output.join("/"))
+}
+
+/// Removes the headers that must not be sent after a redirect from `from` to `to`.
+pub(crate) fn remove_sensitive_headers(headers: &mut HeaderMap, from: &Uri, to: &Uri) {
+  // The Host header is filled in again from the new URI
+  headers.remove(header::HOST);
+  if from.host() != to.host() {
+    headers.remove(header::AUTHORIZATION);
+    headers.remove(header::COOKIE);
+  }
+}
+
+fn same_origin(a: &Uri, b: &Uri) -> bool {
+  a.scheme() == b.scheme() && a.host() == b.host() && a.port_u16() == b.port_u16()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn resolve(location: &str, base: &str) -> String {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::LOCATION, location.parse().unwrap());
+    location_of(&headers, base).unwrap().to_string()
+  }
+
+  fn location_of(headers: &HeaderMap, base: &str) -> Result<Uri, Error> {
+    location(headers, &base.parse().unwrap())
+  }
+
+  #[test]
+  fn relative_locations() {
+    let base = "https://h/dir/file?x=1";
+    assert_eq!(resolve("?q=1", base), "https://h/dir/file?q=1");
+    assert_eq!(resolve("#frag", base), "https://h/dir/file?x=1");
+    assert_eq!(resolve("", base), "https://h/dir/file?x=1");
+    assert_eq!(resolve("../up", base), "https://h/up");
+    assert_eq!(resolve("other", base), "https://h/dir/other");
+    assert_eq!(resolve("/root#frag", base), "https://h/root");
+    assert_eq!(resolve("//cdn.example.com/a/./b", base), "https://cdn.example.com/a/b");
+    assert_eq!(resolve("http://mirror.example.com:8080/x/../y?z", base), "http://mirror.example.com:8080/y?z");
+    assert_eq!(resolve("https://mirror.example.com", base), "https://mirror.example.com/");
+    assert_eq!(resolve("other", "https://h"), "https://h/other");
+  }
+
+  /// The examples of RFC 3986 section 5.4, without the fragments and the references with other schemes.
+  #[test]
+  fn rfc_3986_examples() {
+    let base = "http://a/b/c/d;p?q";
+    let examples = [
+      ("g", "http://a/b/c/g"), ("./g", "http://a/b/c/g"), ("g/", "http://a/b/c/g/"), ("/g", "http://a/g"), ("//g", "http://g/"),
+      ("?y", "http://a/b/c/d;p?y"), ("g?y", "http://a/b/c/g?y"), ("#s", "http://a/b/c/d;p?q"), ("g#s", "http://a/b/c/g"),
+      ("g?y#s", "http://a/b/c/g?y"), (";x", "http://a/b/c/;x"), ("g;x", "http://a/b/c/g;x"), ("g;x?y#s", "http://a/b/c/g;x?y"),
+      ("", "http://a/b/c/d;p?q"), (".", "http://a/b/c/"), ("./", "http://a/b/c/"), ("..", "http://a/b/"), ("../", "http://a/b/"),
+      ("../g", "http://a/b/g"), ("../..", "http://a/"), ("../../", "http://a/"), ("../../g", "http://a/g"),
+      ("../../../g", "http://a/g"), ("../../../../g", "http://a/g"), ("/./g", "http://a/g"), ("/../g", "http://a/g"),
+      ("g.", "http://a/b/c/g."), (".g", "http://a/b/c/.g"), ("g..", "http://a/b/c/g.."), ("..g", "http://a/b/c/..g"),
+      ("./../g", "http://a/b/g"), ("./g/.", "http://a/b/c/g/"), ("g/./h", "http://a/b/c/g/h"), ("g/../h", "http://a/b/c/h"),
+      ("g;x=1/./y", "http://a/b/c/g;x=1/y"), ("g;x=1/../y", "http://a/b/c/y"), ("g?y/./x", "http://a/b/c/g?y/./x"),
+      ("g?y/../x", "http://a/b/c/g?y/../x"), ("g#s/./x", "http://a/b/c/g"), ("g#s/../x", "http://a/b/c/g")
+    ];
+    for (location, expected) in examples {
+      assert_eq!(resolve(location, base), expected, "{}", location);
+    }
+  }
+
+  #[test]
+  fn invalid_locations() {
+    assert!(matches!(location_of(&HeaderMap::new(), "https://h/"), Err(Error::InvalidRedirect(_))));
+    let mut headers = HeaderMap::new();
+    headers.insert(header::LOCATION, "mailto:someone@example.com".parse().unwrap());
+    assert!(matches!(location_of(&headers, "https://h/"), Err(Error::InvalidRedirect(_))));
+  }
+
+  #[test]
+  fn methods_after_redirects() {
+    for status in [StatusCode::MOVED_PERMANENTLY, StatusCode::FOUND] {
+      assert_eq!(next_method(status, &Method::POST), (Method::GET, true));
+      assert_eq!(next_method(status, &Method::GET), (Method::GET, false));
+      assert_eq!(next_method(status, &Method::PUT), (Method::PUT, false));
+      assert_eq!(next_method(status, &Method::HEAD), (Method::HEAD, false));
+    }
+    assert_eq!(next_method(StatusCode::SEE_OTHER, &Method::POST), (Method::GET, true));
+    assert_eq!(next_method(StatusCode::SEE_OTHER, &Method::PUT), (Method::GET, true));
+    assert_eq!(next_method(StatusCode::SEE_OTHER, &Method::HEAD), (Method::HEAD, false));
+    for status in [StatusCode::TEMPORARY_REDIRECT, StatusCode::PERMANENT_REDIRECT] {
+      assert_eq!(next_method(status, &Method::POST), (Method::POST, false));
+      assert_eq!(next_method(status, &Method::PUT), (Method::PUT, false));
+    }
+  }
+
+  #[test]
+  fn credentials_stay_on_their_host() {
+    let headers = || {
+      let mut headers = HeaderMap::new();
+      headers.insert(header::HOST, "a.example.com".parse().unwrap());
+      headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+      headers.insert(header::COOKIE, "session=secret".parse().unwrap());
+      headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+      headers
+    };
+    let from = Uri::from_static("https://a.example.com/file");
+
+    let mut same_host = headers();
+    remove_sensitive_headers(&mut same_host, &from, &Uri::from_static("https://a.example.com:8443/other"));
+    assert!(!same_host.contains_key(header::HOST));
+    assert!(same_host.contains_key(header::AUTHORIZATION) && same_host.contains_key(header::COOKIE));
+
+    let mut other_host = headers();
+    remove_sensitive_headers(&mut other_host, &from, &Uri::from_static("https://b.example.com/file"));
+    assert!(!other_host.contains_key(header::AUTHORIZATION) && !other_host.contains_key(header::COOKIE));
+    assert!(other_host.contains_key(header::ACCEPT));
+  }
+
+  #[test]
+  fn policy_checks() {
+    let (https, http) = (Uri::from_static("https://a/"), Uri::from_static("http://a/"));
+    let other = Uri::from_static("https://b/");
+    let mut policy = RedirectPolicy::default();
+    assert!(policy.check(0, &https, &other, true).is_ok());
+    assert!(matches!(policy.check(0, &https, &http, true), Err(Error::RedirectRejected(_))));
+    assert!(policy.check(0, &https, &http, false).is_ok());
+    assert!(matches!(policy.check(10, &https, &other, true), Err(Error::TooManyRedirects(10))));
+    policy.same_origin_only(true);
+    assert!(matches!(policy.check(0, &https, &other, true), Err(Error::RedirectRejected(_))));
+    assert!(policy.check(0, &https, &Uri::from_static("https://a/other"), true).is_ok());
+    policy.same_origin_only(false).filter(|_, to| to.path() != "/private");
+    assert!(matches!(policy.check(0, &https, &Uri::from_static("https://b/private"), true), Err(Error::RedirectRejected(_))));
+    assert!(RedirectPolicy::none().is_none());
+  }
+}
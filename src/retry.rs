@@ -0,0 +1,173 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+use http::StatusCode;
+use crate::error::Error;
+
+/// Decides whether and when a failed download is attempted again.
+///
+/// Retried downloads continue from the bytes already written when the server supports ranges,
+/// otherwise the resource is downloaded again and the bytes that were already written are skipped.
+///
+/// # Examples
+///
+/// ```
+/// let mut policy = download_async::RetryPolicy::default();
+/// policy.max_attempts(5)
+///       .backoff(std::time::Duration::from_millis(100), std::time::Duration::from_secs(10))
+///       .retry_on_status(&[download_async::http::StatusCode::SERVICE_UNAVAILABLE]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  /// The maximum amount of attempts, including the first one.
+  max_attempts: u32,
+  /// The delay before the first retry, doubled on every subsequent retry.
+  initial_backoff: Duration,
+  /// The upper bound of the delay between two attempts.
+  max_backoff: Duration,
+  /// If set to true, the delay is randomized between half and the full delay.
+  jitter: bool,
+  /// The status codes that are worth another attempt.
+  statuses: Vec<StatusCode>,
+  /// Decides which errors are worth another attempt.
+  retryable: fn(&Error) -> bool
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: 3,
+      initial_backoff: Duration::from_millis(500),
+      max_backoff: Duration::from_secs(30),
+      jitter: true,
+      statuses: vec![
+        StatusCode::REQUEST_TIMEOUT,
+        StatusCode::TOO_MANY_REQUESTS,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        StatusCode::BAD_GATEWAY,
+        StatusCode::SERVICE_UNAVAILABLE,
+        StatusCode::GATEWAY_TIMEOUT,
+      ],
+      retryable: is_transient
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Creates a `RetryPolicy` that never retries.
+  pub fn never() -> Self {
+    let mut policy = Self::default();
+    policy.max_attempts(1);
+    policy
+  }
+
+  /// Sets the maximum amount of attempts, including the first one.
+  pub fn max_attempts(&mut self, max_attempts: u32) -> &mut Self {
+    self.max_attempts = max_attempts.max(1);
+    self
+  }
+
+  /// Sets the delay before the first retry and the upper bound the doubling delay is capped at.
+  pub fn backoff(&mut self, initial: Duration, max: Duration) -> &mut Self {
+    self.initial_backoff = initial;
+    self.max_backoff = max.max(initial);
+    self
+  }
+
+  /// Enables or disables randomizing the delay between attempts.
+  pub fn jitter(&mut self, jitter: bool) -> &mut Self {
+    self.jitter = jitter;
+    self
+  }
+
+  /// Sets the status codes that are worth another attempt, replacing the defaults.
+  pub fn retry_on_status(&mut self, statuses: &[StatusCode]) -> &mut Self {
+    self.statuses = statuses.to_vec();
+    self
+  }
+
+  /// Sets which errors are worth another attempt, replacing the default of connection, body, decoding and timeout errors.
+  ///
+  /// `Error::StatusError` is decided by `retry_on_status` instead.
+  pub fn retry_if(&mut self, retryable: fn(&Error) -> bool) -> &mut Self {
+    self.retryable = retryable;
+    self
+  }
+
+  /// Whether `attempt` failed with `error` may be followed by another attempt.
+  pub(crate) fn should_retry(&self, attempt: u32, error: &Error) -> bool {
+    if attempt >= self.max_attempts {
+      return false;
+    }
+    match error {
+      Error::StatusError(status) => self.statuses.contains(status),
+      error => (self.retryable)(error)
+    }
+  }
+
+  /// The delay to wait after `attempt` failed.
+  pub(crate) fn delay(&self, attempt: u32) -> Duration {
+    let delay = self.initial_backoff
+      .checked_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+      .unwrap_or(self.max_backoff)
+      .min(self.max_backoff);
+    if self.jitter {
+      let half = delay / 2;
+      let jitter = fastrand::u64(0..=u64::try_from((delay - half).as_nanos()).unwrap_or(u64::MAX));
+      half + Duration::from_nanos(jitter)
+    } else {
+      delay
+    }
+  }
+}
+
+/// The errors that may disappear by trying again.
+fn is_transient(error: &Error) -> bool {
+  matches!(error, Error::HyperError(_) | Error::InvalidBody(_) | Error::Decode(_) | Error::TimedOut(_))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn delays_double_up_to_the_maximum() {
+    let mut policy = RetryPolicy::default();
+    policy.backoff(Duration::from_millis(100), Duration::from_millis(500)).jitter(false);
+    let delays: Vec<_> = (1..=6).map(|attempt| policy.delay(attempt).as_millis()).collect();
+    assert_eq!(delays, [100, 200, 400, 500, 500, 500]);
+    assert_eq!(policy.delay(u32::MAX), Duration::from_millis(500));
+  }
+
+  #[test]
+  fn jittered_delays_stay_within_the_bounds() {
+    let mut policy = RetryPolicy::default();
+    policy.backoff(Duration::from_millis(100), Duration::from_secs(1));
+    for attempt in 1..=10 {
+      let full = Duration::from_millis(100 * 2_u64.pow(attempt - 1)).min(Duration::from_secs(1));
+      for _ in 0..100 {
+        let delay = policy.delay(attempt);
+        assert!(delay >= full / 2 && delay <= full, "attempt {}: {:?} outside {:?}..={:?}", attempt, delay, full / 2, full);
+      }
+    }
+  }
+
+  #[test]
+  fn jittered_delays_vary() {
+    let mut policy = RetryPolicy::default();
+    policy.backoff(Duration::from_secs(10), Duration::from_secs(10));
+    let delays: std::collections::HashSet<_> = (0..20).map(|_| policy.delay(1)).collect();
+    assert!(delays.len() > 1);
+  }
+
+  #[test]
+  fn should_retry() {
+    let mut policy = RetryPolicy::default();
+    policy.max_attempts(2);
+    assert!(policy.should_retry(1, &Error::StatusError(StatusCode::SERVICE_UNAVAILABLE)));
+    assert!(!policy.should_retry(2, &Error::StatusError(StatusCode::SERVICE_UNAVAILABLE)));
+    assert!(!policy.should_retry(1, &Error::StatusError(StatusCode::NOT_FOUND)));
+    assert!(policy.should_retry(1, &Error::TimedOut(crate::error::Timeout::Read)));
+    assert!(!policy.should_retry(1, &Error::ResourceChanged));
+    assert!(!RetryPolicy::never().should_retry(1, &Error::TimedOut(crate::error::Timeout::Read)));
+  }
+}
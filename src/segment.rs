@@ -0,0 +1,228 @@
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+use async_trait::async_trait;
+use hyper::body::HttpBody;
+use http::header;
+use http::response::Parts;
+use tokio::io::AsyncWrite;
+use crate::download::{self, Options};
+use crate::error::{Error, Timeout};
+use crate::progress::Progress;
+use crate::range::{ByteRange, RangeSet};
+use crate::writer::BlockingWriter;
+
+type Request<T> = crate::http::Request<T>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where the segments of a download are written, each through its own writer.
+#[async_trait]
+pub(crate) trait Destination: Sync {
+    /// The position the first byte of the download is written at.
+    async fn position(&self) -> Result<u64, Error>;
+
+    /// A writer that writes at `position`, adding the ranges of the resource from `resource_position` on to `written` once they are written.
+    async fn writer<'a>(&'a self, position: u64, resource_position: u64, written: &'a Mutex<RangeSet>) -> Result<Box<dyn AsyncWrite + Unpin + Send + 'a>, Error>;
+}
+
+/// A seekable writer that all segments write to in turn, blocking while they do.
+pub(crate) struct Shared<'a, W>(Mutex<&'a mut W>);
+
+/// Writes the bytes of one segment at their position in the shared destination.
+struct SegmentWriter<'a, W> {
+    destination: &'a Mutex<W>,
+    position: u64,
+    /// The ranges of the resource that have been written by all segments.
+    written: &'a Mutex<RangeSet>,
+    /// The position in the resource the next byte belongs at.
+    resource_position: u64,
+}
+
+/// Forwards the progress of one segment to the progress tracker shared by all segments.
+///
+/// A segment reports its position in the whole resource, of which only the bytes within the segment are forwarded.
+struct SegmentProgress {
+    shared: Arc<tokio::sync::Mutex<Box<dyn Progress + Send>>>,
+    segment: ByteRange,
+    position: u64,
+}
+
+/// Downloads the resource in `options.segments` parallel ranges, writing each at its offset from the position of `to`.
+///
+/// The ranges of the resource in `completed` are skipped, and the ranges are added to it as soon as they are written, even when the download fails.
+/// Falls back to a single stream when the server doesn't support ranges or encodes the body.
+pub(crate) async fn download<T: HttpBody + Send + 'static>(request: Request<T>, to: &impl Destination, progress: &mut Option<Box<dyn Progress + Send>>, mut options: Options, completed: &Mutex<RangeSet>) -> Result<Parts, Error> where T::Data: Send, T::Error: Into<BoxError> {
+    let total = options.timeouts.total.take();
+    let download = async {
+        let (head, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(|e| Error::InvalidBody(e.into()))?;
+        let request = Request::from_parts(head, body);
+
+        // Segments arrive out of order, so they can't be hashed as they are written
+        if options.segments <= 1 || !options.digests.is_empty() {
+            return single(request, to, progress, options, completed).await;
+        }
+
+        // Every segment uses its own connection from the same pool
+        let client = options.client()?;
+        options.client = Some(client.clone());
+        // The probe and every segment share the validators, so the segments can't be of different versions of the resource
+        let validator = options.validator.get_or_insert_with(Default::default).clone();
+        let probed = match download::probe(&client, &request, &options).await {
+            Ok((parts, Some(length))) => Some((parts, length)),
+            Ok(_) => None,
+            Err(Error::ResourceChanged) => return Err(Error::ResourceChanged),
+            Err(error) => {
+                log::debug!("Probing {} for range support failed with {:?}", request.uri(), error);
+                None
+            }
+        };
+        let (parts, length) = match probed {
+            Some(probed) => probed,
+            None => return single(request, to, progress, options, completed).await,
+        };
+
+        let start = options.range.map_or(0, |range| range.start);
+        let end = options.range.and_then(|range| range.end).map_or(length, |end| end.min(length));
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.set_file_size(end as usize).await;
+            let covered = completed.lock().unwrap_or_else(PoisonError::into_inner).covered(start..end);
+            progress.add_to_progress((start + covered) as usize).await;
+        }
+        if start >= end {
+            // Resuming a download that was already complete
+            return Ok(parts);
+        }
+
+        // The final URI is requested right away, instead of following the same redirects for every segment
+        let mut request = request;
+        if let Some(redirects) = parts.extensions.get::<crate::redirect::RedirectChain>() {
+            *request.uri_mut() = redirects.final_uri().clone();
+        }
+        // A server that changed the resource since the probe sends all of it, instead of a segment of the new version
+        if !request.headers().contains_key(header::IF_RANGE) {
+            if let Some(if_range) = validator.lock().unwrap_or_else(PoisonError::into_inner).if_range() {
+                request.headers_mut().insert(header::IF_RANGE, if_range);
+            }
+        }
+
+        let origin = to.position().await?;
+        let shared = progress.take().map(|progress| Arc::new(tokio::sync::Mutex::new(progress)));
+
+        let count = (options.segments as u64).min(end.saturating_sub(start)).max(1);
+        let size = (end - start) / count;
+        // Every segment only downloads the parts that haven't been completed before
+        let missing: Vec<_> = {
+            let completed = completed.lock().unwrap_or_else(PoisonError::into_inner);
+            (0..count).flat_map(|index| {
+                let segment_end = if index == count - 1 { end } else { start + (index + 1) * size };
+                completed.missing(start + index * size..segment_end)
+            }).collect()
+        };
+        let segments = missing.into_iter().map(|missing| {
+            let segment = ByteRange { start: missing.start, end: Some(missing.end) };
+            let mut options = options.clone();
+            options.range = Some(segment);
+            let request = download::copy_request(&request).map(hyper::Body::from);
+            let mut progress = shared.clone().map(|shared| Box::new(SegmentProgress { shared, segment, position: 0 }) as Box<dyn Progress + Send>);
+            async move {
+                let mut writer = to.writer(origin + segment.start - start, segment.start, completed).await?;
+                download::download(request, &mut writer, &mut progress, options).await
+            }
+        });
+        let result = futures_util::future::try_join_all(segments).await;
+
+        if let Some(shared) = shared {
+            *progress = Arc::try_unwrap(shared).ok().map(|shared| shared.into_inner());
+        }
+        result?;
+        Ok(parts)
+    };
+
+    match total {
+        Some(total) => tokio::time::timeout(total, download).await.map_err(|_| Error::TimedOut(Timeout::Total))?,
+        None => download.await,
+    }
+}
+
+/// Downloads the resource as a single stream from the position of `to`, adding the written bytes to `completed`.
+async fn single(request: Request<bytes::Bytes>, to: &impl Destination, progress: &mut Option<Box<dyn Progress + Send>>, options: Options, completed: &Mutex<RangeSet>) -> Result<Parts, Error> {
+    let start = options.range.map_or(0, |range| range.start);
+    // The stream overwrites everything after its start
+    completed.lock().unwrap_or_else(PoisonError::into_inner).truncate(start);
+    let mut writer = to.writer(to.position().await?, start, completed).await?;
+    download::download(request.map(hyper::Body::from), &mut writer, progress, options).await
+}
+
+impl<'a, W> Shared<'a, W> {
+    pub(crate) fn new(to: &'a mut W) -> Self {
+        Self(Mutex::new(to))
+    }
+}
+
+#[async_trait]
+impl<W: Write + Seek + Send> Destination for Shared<'_, W> {
+    async fn position(&self) -> Result<u64, Error> {
+        let mut destination = self.0.lock().map_err(|_| std::io::Error::other("A segment panicked while writing"))?;
+        Ok(destination.stream_position()?)
+    }
+
+    async fn writer<'a>(&'a self, position: u64, resource_position: u64, written: &'a Mutex<RangeSet>) -> Result<Box<dyn AsyncWrite + Unpin + Send + 'a>, Error> {
+        Ok(Box::new(BlockingWriter(SegmentWriter {
+            destination: &self.0,
+            position,
+            written,
+            resource_position,
+        })))
+    }
+}
+
+impl<W: Write + Seek> Write for SegmentWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut destination = self.destination.lock().map_err(|_| std::io::Error::other("Another segment panicked while writing"))?;
+        destination.seek(SeekFrom::Start(self.position))?;
+        destination.write_all(buf)?;
+        self.position += buf.len() as u64;
+        let written = self.resource_position..self.resource_position + buf.len() as u64;
+        self.written.lock().unwrap_or_else(PoisonError::into_inner).insert(written);
+        self.resource_position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut destination = self.destination.lock().map_err(|_| std::io::Error::other("Another segment panicked while writing"))?;
+        destination.flush()
+    }
+}
+
+impl SegmentProgress {
+    /// The amount of bytes within this segment that lie before `position`.
+    fn within(&self, position: u64) -> u64 {
+        let end = self.segment.end.unwrap_or(u64::MAX);
+        position.clamp(self.segment.start, end) - self.segment.start
+    }
+}
+
+#[async_trait]
+impl Progress for SegmentProgress {
+    async fn set_file_size(&mut self, _size: usize) {
+        // The size of the whole resource has already been set.
+    }
+
+    async fn add_to_progress(&mut self, amount: usize) {
+        let before = self.within(self.position);
+        self.position += amount as u64;
+        let added = self.within(self.position) - before;
+        if added > 0 {
+            self.shared.lock().await.add_to_progress(added as usize).await;
+        }
+    }
+
+    async fn remove_from_progress(&mut self, amount: usize) {
+        let before = self.within(self.position);
+        self.position = self.position.saturating_sub(amount as u64);
+        let removed = before - self.within(self.position);
+        if removed > 0 {
+            self.shared.lock().await.remove_from_progress(removed as usize).await;
+        }
+    }
+}
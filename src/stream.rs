@@ -0,0 +1,205 @@
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use futures_core::Stream;
+use http::response::Parts;
+use hyper::body::HttpBody;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+use tokio::time::Instant;
+use tokio_util::io::StreamReader;
+use crate::checksum::Hasher;
+use crate::client::DownloadClient;
+use crate::download::{self, Options, ResponseBody};
+use crate::error::{Error, Timeout};
+use crate::progress::Progress;
+
+type Request<T> = crate::http::Request<T>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The decoded body of a response as a `Stream` of chunks, returned by `Downloader::stream`.
+///
+/// The stream ends with an error when the body can't be received, or doesn't have the expected digests.
+pub struct DownloadStream {
+    inner: Pin<Box<dyn Stream<Item = Result<Bytes, Error>> + Send>>,
+}
+
+/// The decoded body of a response as an `AsyncRead`, returned by `Downloader::into_async_read`.
+///
+/// Reading fails with an `std::io::Error` that contains the `Error` the `DownloadStream` ended with.
+pub struct DownloadReader {
+    inner: StreamReader<IoStream, Bytes>,
+}
+
+/// A `DownloadStream` that fails with `std::io::Error`s, as `StreamReader` requires.
+struct IoStream(DownloadStream);
+
+/// What's left to do after the response has been received.
+struct State {
+    /// The body that is being received, `None` once it ended.
+    body: Option<ResponseBody>,
+    progress: Option<Box<dyn Progress + Send>>,
+    /// Hash the received bytes for every expected digest.
+    hashers: Vec<Hasher>,
+    /// When the total timeout expires.
+    deadline: Option<Instant>,
+}
+
+/// Sends the request, retrying until the response has been received, and returns its body as a stream.
+pub(crate) async fn stream<T: HttpBody + Send + 'static>(request: Request<T>, mut progress: Option<Box<dyn Progress + Send>>, options: Options) -> Result<(Parts, DownloadStream), Error> where T::Data: Send, T::Error: Into<BoxError> {
+    let deadline = options.timeouts.total.map(|total| Instant::now() + total);
+    let open = async {
+        // The body is buffered so it can be sent again when retrying.
+        let (head, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(|e| Error::InvalidBody(e.into()))?;
+        let request = Request::from_parts(head, body);
+
+        let client = options.client()?;
+        let mut attempt = 1;
+        loop {
+            match open_once(&client, download::copy_request(&request), &options).await {
+                Err(error) if options.retry.should_retry(attempt, &error) => {
+                    let delay = options.retry.delay(attempt);
+                    log::warn!("Attempt {} to download {} failed with {:?}, retrying in {:?}", attempt, request.uri(), error, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    };
+    let (parts, body) = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, open).await.map_err(|_| Error::TimedOut(Timeout::Total))??,
+        None => open.await?,
+    };
+
+    if let Some(progress) = progress.as_deref_mut() {
+        let (file_size, position) = match body {
+            Some(ref body) => (body.file_size, body.position),
+            // Resuming a download that was already complete
+            None => (options.range.map(|range| range.start), options.range.map_or(0, |range| range.start)),
+        };
+        if let Some(file_size) = file_size {
+            progress.set_file_size(file_size as usize).await;
+        }
+        progress.add_to_progress(position as usize).await;
+    }
+
+    let state = State {
+        body,
+        progress,
+        hashers: options.digests.into_iter().map(Hasher::new).collect(),
+        deadline,
+    };
+    let inner = futures_util::stream::unfold(state, |mut state| async move {
+        let item = state.next().await?;
+        Some((item, state))
+    });
+    Ok((parts, DownloadStream { inner: Box::pin(inner) }))
+}
+
+/// Sends the request, returning the body of the response when there's anything left to download.
+async fn open_once(client: &DownloadClient, request: Request<Bytes>, options: &Options) -> Result<(Parts, Option<ResponseBody>), Error> {
+    let (res, redirects) = download::send_following_redirects(client, request, options).await?;
+    let status = res.status();
+    let (mut parts, body) = res.into_parts();
+    parts.extensions.insert(redirects);
+
+    if download::already_complete(&parts, options.range).is_some() {
+        return Ok((parts, None));
+    }
+    if status == 200 || status == 206 {
+        let body = ResponseBody::new(&mut parts, body, options.range, options)?;
+        Ok((parts, Some(body)))
+    } else {
+        Err(Error::StatusError(status))
+    }
+}
+
+impl State {
+    async fn next(&mut self) -> Option<Result<Bytes, Error>> {
+        let body = self.body.as_mut()?;
+        loop {
+            let next = match self.deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, body.next()).await.unwrap_or(Some(Err(Error::TimedOut(Timeout::Total)))),
+                None => body.next().await,
+            };
+            match next {
+                Some(Ok((skipped, chunk))) => {
+                    if let Some(progress) = self.progress.as_deref_mut() {
+                        // Skipped bytes have been downloaded as well
+                        progress.add_to_progress(skipped as usize + chunk.len()).await;
+                    }
+                    for hasher in self.hashers.iter_mut() {
+                        hasher.update(&chunk);
+                    }
+                    if !chunk.is_empty() {
+                        return Some(Ok(chunk));
+                    }
+                },
+                Some(Err(error)) => {
+                    self.body = None;
+                    return Some(Err(error));
+                },
+                None => {
+                    // The stream only ends successfully when the body has the expected digests
+                    let result = self.body.take()?.finish()
+                        .and_then(|()| std::mem::take(&mut self.hashers).into_iter().try_for_each(Hasher::verify));
+                    return result.err().map(Err);
+                },
+            }
+        }
+    }
+}
+
+impl Stream for DownloadStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl fmt::Debug for DownloadStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DownloadStream").finish()
+    }
+}
+
+impl DownloadReader {
+    pub(crate) fn new(stream: DownloadStream) -> Self {
+        Self {
+            inner: StreamReader::new(IoStream(stream)),
+        }
+    }
+}
+
+impl AsyncRead for DownloadReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for DownloadReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amount: usize) {
+        Pin::new(&mut self.inner).consume(amount)
+    }
+}
+
+impl fmt::Debug for DownloadReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DownloadReader").finish()
+    }
+}
+
+impl Stream for IoStream {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx).map(|item| item.map(|item| item.map_err(crate::error::into_io)))
+    }
+}
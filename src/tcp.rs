@@ -0,0 +1,122 @@
+use std::net::{IpAddr, SocketAddr};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use http::Uri;
+use http::uri::Scheme;
+use hyper::client::HttpConnector;
+use hyper::client::connect::dns::Name;
+use tokio::net::{TcpSocket, TcpStream};
+use crate::dns::ResolverService;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Opens the TCP connections, with hyper's `HttpConnector` unless they have to be bound to a network interface.
+#[derive(Clone)]
+pub(crate) struct TcpConnector {
+  http: HttpConnector<ResolverService>,
+  resolver: ResolverService,
+  /// The network interface the connections are bound to.
+  device: Option<String>,
+  /// The local IP address the connections are made from.
+  local_address: Option<IpAddr>,
+  /// The maximum time to establish a connection.
+  connect_timeout: Option<Duration>
+}
+
+impl TcpConnector {
+  pub(crate) fn new(mut resolver: ResolverService, device: Option<String>, local_address: Option<IpAddr>, connect_timeout: Option<Duration>) -> Self {
+    resolver.set_local_address(local_address);
+    let mut http = HttpConnector::new_with_resolver(resolver.clone());
+    // The https connector hands https URIs to the http connector as well
+    http.enforce_http(false);
+    http.set_connect_timeout(connect_timeout);
+    http.set_local_address(local_address);
+    Self { http, resolver, device, local_address, connect_timeout }
+  }
+
+  /// Sets how long to wait before trying the other IP version, see `Downloader::happy_eyeballs_timeout`.
+  pub(crate) fn set_happy_eyeballs_timeout(&mut self, timeout: Option<Duration>) {
+    self.http.set_happy_eyeballs_timeout(timeout);
+  }
+
+  /// The resolver the hosts are resolved with, keeping the addresses of both IP versions.
+  pub(crate) fn resolver(&self) -> ResolverService {
+    let mut resolver = self.resolver.clone();
+    resolver.set_local_address(None);
+    resolver
+  }
+
+  pub(crate) fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+    tower::Service::poll_ready(&mut self.http, cx).map_err(Into::into)
+  }
+
+  /// Connects to the host and port of `uri`.
+  pub(crate) async fn connect(mut self, uri: Uri) -> Result<TcpStream, BoxError> {
+    // IP addresses in the URI aren't resolved, so they aren't filtered by the resolver
+    let ip = uri.host().and_then(|host| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok());
+    if let (Some(ip), Some(local_address)) = (ip, self.local_address) {
+      if ip.is_ipv4() != local_address.is_ipv4() {
+        return Err(format!("{} can't be connected to from the local address {}", ip, local_address).into());
+      }
+    }
+    match self.device.take() {
+      Some(device) => {
+        let connect_timeout = self.connect_timeout;
+        let connecting = self.connect_device(device, uri);
+        match connect_timeout {
+          Some(timeout) => tokio::time::timeout(timeout, connecting).await.map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"))?,
+          None => connecting.await
+        }
+      }
+      None => Ok(tower::Service::call(&mut self.http, uri).await?)
+    }
+  }
+
+  /// Connects from `device`, trying the addresses of the host one after another.
+  async fn connect_device(&mut self, device: String, uri: Uri) -> Result<TcpStream, BoxError> {
+    let host = uri.host().ok_or_else(|| format!("{} has no host", uri))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(if uri.scheme() == Some(&Scheme::HTTPS) { 443 } else { 80 });
+    let addresses: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+      Ok(ip) => vec![SocketAddr::new(ip, port)],
+      Err(_) => tower::Service::call(&mut self.resolver, host.parse::<Name>()?).await?
+        .map(|address| SocketAddr::new(address.ip(), port))
+        .collect()
+    };
+
+    let mut last_error = None;
+    for address in addresses {
+      match self.connect_address(&device, address).await {
+        Ok(stream) => return Ok(stream),
+        Err(error) => last_error = Some(error)
+      }
+    }
+    Err(match last_error {
+      Some(error) => Box::new(error),
+      None => format!("{} didn't resolve to any address", host).into()
+    })
+  }
+
+  /// Connects to `address` from `device`, and from the local address if there is one.
+  async fn connect_address(&self, device: &str, address: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = match address {
+      SocketAddr::V4(_) => TcpSocket::new_v4()?,
+      SocketAddr::V6(_) => TcpSocket::new_v6()?
+    };
+    bind_device(&socket, device)?;
+    if let Some(local_address) = self.local_address {
+      socket.bind(SocketAddr::new(local_address, 0))?;
+    }
+    socket.connect(address).await
+  }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, device: &str) -> std::io::Result<()> {
+  socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_: &TcpSocket, _: &str) -> std::io::Result<()> {
+  Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "binding to a network interface is only supported on Linux"))
+}
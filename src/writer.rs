@@ -0,0 +1,21 @@
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+/// Lets a blocking `std::io::Write` be written to like an `AsyncWrite`, every write completing right away.
+pub(crate) struct BlockingWriter<W>(pub(crate) W);
+
+impl<W: Write + Unpin> AsyncWrite for BlockingWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(self.0.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
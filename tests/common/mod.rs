@@ -0,0 +1,137 @@
+//! A minimal HTTP server to test downloads against, handing every connection to a handler.
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine as _;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The certificate authority issuing the certificates of `fixtures`.
+pub const CA: &[u8] = include_bytes!("../fixtures/ca.pem");
+
+/// The head of a request the server received.
+#[derive(Debug, Clone)]
+pub struct Head {
+  /// The request line, like `GET /file HTTP/1.1`.
+  pub line: String,
+  pub headers: Vec<(String, String)>
+}
+
+impl Head {
+  /// The value of the header `name`, compared case-insensitively.
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+  }
+}
+
+/// Reads the head of the next request on `stream`, `None` when the connection is closed before.
+pub async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Option<Head> {
+  let mut buffer = Vec::new();
+  let mut byte = [0; 1];
+  while !buffer.ends_with(b"\r\n\r\n") {
+    if stream.read(&mut byte).await.ok()? == 0 {
+      return None;
+    }
+    buffer.push(byte[0]);
+  }
+  let text = String::from_utf8(buffer).ok()?;
+  let mut lines = text.split("\r\n").filter(|line| !line.is_empty());
+  let line = lines.next()?.to_string();
+  let headers = lines.filter_map(|line| line.split_once(':')).map(|(name, value)| (name.to_string(), value.trim().to_string())).collect();
+  Some(Head { line, headers })
+}
+
+/// Listens on a local port, calling `handler` with every connection along with the head of its first request.
+pub async fn serve<F, R>(handler: F) -> SocketAddr where F: Fn(Head, TcpStream) -> R + Send + Sync + 'static, R: Future<Output = ()> + Send + 'static {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  let handler = Arc::new(handler);
+  tokio::spawn(async move {
+    while let Ok((mut stream, _)) = listener.accept().await {
+      let handler = handler.clone();
+      tokio::spawn(async move {
+        if let Some(head) = read_head(&mut stream).await {
+          handler(head, stream).await;
+        }
+      });
+    }
+  });
+  address
+}
+
+/// Listens on `address` without accepting, filling the queue of the listener so new connections never finish connecting.
+///
+/// The queued connections and the listener have to be kept for as long as the address should stay unreachable.
+pub async fn unreachable(address: SocketAddr) -> std::io::Result<(SocketAddr, Vec<TcpStream>, TcpListener)> {
+  let socket = if address.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+  socket.bind(address)?;
+  let listener = socket.listen(1)?;
+  let address = listener.local_addr()?;
+  let mut queued = vec![];
+  while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(address)).await {
+    queued.push(stream);
+  }
+  Ok((address, queued, listener))
+}
+
+/// Decodes the PEM blocks labelled `label` in `pem`.
+pub fn pem_der(pem: &str, label: &str) -> Vec<Vec<u8>> {
+  let (begin, end) = (format!("-----BEGIN {}-----", label), format!("-----END {}-----", label));
+  pem.split(begin.as_str()).skip(1)
+    .map(|block| block.split(end.as_str()).next().unwrap().split_whitespace().collect::<String>())
+    .map(|contents| base64::engine::general_purpose::STANDARD.decode(contents).unwrap())
+    .collect()
+}
+
+/// The configuration of a TLS server for `localhost`, with a certificate issued by `CA`.
+///
+/// With `client_auth`, the clients have to present a certificate issued by it as well.
+pub fn server_config(client_auth: bool) -> rustls::ServerConfig {
+  let chain = pem_der(include_str!("../fixtures/server.pem"), "CERTIFICATE").into_iter().map(rustls::Certificate).collect();
+  let key = rustls::PrivateKey(pem_der(include_str!("../fixtures/server.key"), "PRIVATE KEY").remove(0));
+  let builder = rustls::ServerConfig::builder().with_safe_defaults();
+  let builder = if client_auth {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(pem_der(std::str::from_utf8(CA).unwrap(), "CERTIFICATE").remove(0))).unwrap();
+    builder.with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed())
+  } else {
+    builder.with_no_client_auth()
+  };
+  builder.with_single_cert(chain, key).unwrap()
+}
+
+/// Listens over TLS on a local port, configured by `server_config`, answering every request with `reply`.
+pub async fn serve_tls<F>(client_auth: bool, reply: F) -> SocketAddr where F: Fn(Head) -> Vec<u8> + Send + Sync + 'static {
+  let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config(client_auth)));
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  let reply = Arc::new(reply);
+  tokio::spawn(async move {
+    while let Ok((stream, _)) = listener.accept().await {
+      let (acceptor, reply) = (acceptor.clone(), reply.clone());
+      tokio::spawn(async move {
+        // The handshakes the tests expect to fail end here
+        if let Ok(mut stream) = acceptor.accept(stream).await {
+          if let Some(head) = read_head(&mut stream).await {
+            let _ = stream.write_all(&reply(head)).await;
+            let _ = stream.shutdown().await;
+          }
+        }
+      });
+    }
+  });
+  address
+}
+
+/// The head of a response, closing the connection after it so every request is made on a new one.
+pub fn response(status: &str, headers: &[(&str, &str)]) -> Vec<u8> {
+  let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+  for (name, value) in headers {
+    response.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  response.push_str("\r\n");
+  response.into_bytes()
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use std::error::Error as _;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use download_async::{AddressPreference, Body, Downloader, Error, RetryPolicy};
+use download_async::http::Uri;
+use common::{response, serve};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn downloader(uri: &str) -> Downloader {
+  let mut downloader = Downloader::new();
+  downloader.use_uri(uri.parse::<Uri>().unwrap()).allow_http().retry(RetryPolicy::never()).local_address(LOCALHOST);
+  downloader
+}
+
+/// The messages of `error` and its sources.
+fn messages(error: &Error) -> String {
+  let mut messages = format!("{:?}", error);
+  let mut source = error.source();
+  while let Some(error) = source {
+    messages.push_str(&format!(": {}", error));
+    source = error.source();
+  }
+  messages
+}
+
+#[tokio::test]
+async fn local_address_only_connects_to_its_ip_version() {
+  let address = serve(|_, mut stream| async move {
+    stream.write_all(&response("200 OK", &[("Content-Length", "2")])).await.unwrap();
+    stream.write_all(b"ok").await.unwrap();
+  }).await;
+  let mut downloader = downloader(&format!("http://example.test:{}/file", address.port()));
+  downloader
+    .resolve("example.test", SocketAddr::new("::1".parse().unwrap(), 0))
+    .resolve("example.test", SocketAddr::new(LOCALHOST, 0))
+    .happy_eyeballs_timeout(None);
+  let mut buffer = vec![];
+  downloader.download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, b"ok");
+}
+
+#[tokio::test]
+async fn local_address_fails_a_host_of_the_other_ip_version() {
+  let mut downloader = downloader("http://example.test:1/file");
+  downloader.resolve("example.test", SocketAddr::new("::1".parse().unwrap(), 0));
+  let error = downloader.download(Body::empty(), &mut vec![]).await.unwrap_err();
+  assert!(messages(&error).contains("no addresses of the IP version of the local address 127.0.0.1"), "{}", messages(&error));
+}
+
+#[tokio::test]
+async fn local_address_fails_an_address_of_the_other_ip_version() {
+  let error = downloader("http://[::1]:1/file").download(Body::empty(), &mut vec![]).await.unwrap_err();
+  assert!(messages(&error).contains("::1 can't be connected to from the local address 127.0.0.1"), "{}", messages(&error));
+}
+
+#[tokio::test]
+async fn happy_eyeballs_falls_back_to_the_other_ip_version() {
+  // IPv6 never connects on the port that IPv4 is served on
+  let (address, _unreachable) = loop {
+    let address = serve(|_, mut stream| async move {
+      stream.write_all(&response("200 OK", &[("Content-Length", "2")])).await.unwrap();
+      stream.write_all(b"ok").await.unwrap();
+    }).await;
+    if let Ok(unreachable) = common::unreachable(SocketAddr::new("::1".parse().unwrap(), address.port())).await {
+      break (address, unreachable);
+    }
+  };
+  let mut downloader = Downloader::new();
+  downloader.use_uri(format!("http://example.test:{}/file", address.port()).parse::<Uri>().unwrap())
+    .allow_http()
+    .retry(RetryPolicy::never())
+    .resolve("example.test", SocketAddr::new("::1".parse().unwrap(), 0))
+    .resolve("example.test", SocketAddr::new(LOCALHOST, 0))
+    .address_preference(AddressPreference::PreferIpv6)
+    .happy_eyeballs_timeout(Some(Duration::from_millis(100)));
+  let started = Instant::now();
+  let mut buffer = vec![];
+  downloader.download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, b"ok");
+  let elapsed = started.elapsed();
+  assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+}
@@ -0,0 +1,358 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use download_async::{Body, Downloader, Error, RetryPolicy};
+use download_async::http::Uri;
+use common::{response, serve, Head};
+
+const BODY: &[u8] = b"0123456789";
+
+fn downloader(address: SocketAddr) -> Downloader {
+  let mut downloader = Downloader::new();
+  downloader.use_uri(format!("http://{}/file", address).parse::<Uri>().unwrap()).allow_http();
+  let mut policy = RetryPolicy::default();
+  policy.max_attempts(2).backoff(Duration::from_millis(10), Duration::from_millis(10));
+  downloader.retry(policy);
+  downloader
+}
+
+/// Sends the first half of `BODY` with the ETag `"v1"` before closing the connection, and answers the retry with `retry`.
+async fn interrupted(retry: fn(&Head) -> Vec<u8>) -> (SocketAddr, Arc<Mutex<Vec<Head>>>) {
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  let address = serve(move |head, mut stream| {
+    let attempt = {
+      let mut requests = received.lock().unwrap();
+      requests.push(head.clone());
+      requests.len()
+    };
+    async move {
+      let mut reply = match attempt {
+        1 => response("200 OK", &[("ETag", "\"v1\""), ("Content-Length", "10")]),
+        _ => retry(&head)
+      };
+      if attempt == 1 {
+        reply.extend_from_slice(&BODY[..5]);
+      }
+      stream.write_all(&reply).await.unwrap();
+    }
+  }).await;
+  (address, requests)
+}
+
+#[tokio::test]
+async fn empty_range_fails_before_requesting() {
+  for range in [5..5, std::ops::Range { start: 10, end: 3 }] {
+    let mut downloader = Downloader::new();
+    // Nothing listens on the discard port, so a request would fail with another error
+    downloader.use_uri(Uri::from_static("http://127.0.0.1:9/file")).allow_http().range(range.clone());
+    let mut buffer = vec![];
+    match downloader.download(Body::empty(), &mut buffer).await {
+      Err(Error::EmptyRange(empty)) => assert_eq!(empty, range),
+      result => panic!("expected Error::EmptyRange, got {:?}", result)
+    }
+  }
+}
+
+#[tokio::test]
+async fn retry_continues_the_same_version() {
+  let (address, requests) = interrupted(|_| {
+    let mut reply = response("206 Partial Content", &[("ETag", "\"v1\""), ("Content-Range", "bytes 5-9/10"), ("Content-Length", "5")]);
+    reply.extend_from_slice(&BODY[5..]);
+    reply
+  }).await;
+
+  let mut buffer = vec![];
+  downloader(address).download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, BODY);
+
+  let requests = requests.lock().unwrap();
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[1].header("Range"), Some("bytes=5-"));
+  assert_eq!(requests[1].header("If-Range"), Some("\"v1\""));
+  assert_eq!(requests[1].header("Accept-Encoding"), Some("identity"));
+}
+
+#[tokio::test]
+async fn retry_fails_when_the_resource_changed() {
+  let retries: [fn(&Head) -> Vec<u8>; 2] = [
+    // The server ignored the Range because of the If-Range
+    |_| {
+      let mut reply = response("200 OK", &[("ETag", "\"v2\""), ("Content-Length", "10")]);
+      reply.extend_from_slice(b"abcdefghij");
+      reply
+    },
+    // The server ignored the If-Range
+    |_| {
+      let mut reply = response("206 Partial Content", &[("ETag", "\"v2\""), ("Content-Range", "bytes 5-9/10"), ("Content-Length", "5")]);
+      reply.extend_from_slice(b"fghij");
+      reply
+    },
+  ];
+  for retry in retries {
+    let (address, _) = interrupted(retry).await;
+    let mut buffer = vec![];
+    let result = downloader(address).download(Body::empty(), &mut buffer).await;
+    assert!(matches!(result, Err(Error::ResourceChanged)), "expected Error::ResourceChanged, got {:?}", result);
+    assert_eq!(buffer, &BODY[..5]);
+  }
+}
+
+#[tokio::test]
+async fn ranges_are_requested_without_encoding() {
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  let address = serve(move |head, mut stream| {
+    received.lock().unwrap().push(head);
+    async move {
+      let mut reply = response("206 Partial Content", &[("Content-Range", "bytes 2-4/10"), ("Content-Length", "3")]);
+      reply.extend_from_slice(&BODY[2..5]);
+      stream.write_all(&reply).await.unwrap();
+    }
+  }).await;
+
+  let mut buffer = vec![];
+  let mut downloader = downloader(address);
+  downloader.range(2..5);
+  downloader.download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, &BODY[2..5]);
+  let requests = requests.lock().unwrap();
+  assert_eq!(requests[0].header("Range"), Some("bytes=2-4"));
+  assert_eq!(requests[0].header("Accept-Encoding"), Some("identity"));
+}
+
+/// Sends `BODY` in two halves, waiting `pause` in between.
+async fn two_halves(pause: Duration) -> SocketAddr {
+  serve(move |_, mut stream| async move {
+    stream.write_all(&response("200 OK", &[("Content-Length", "10")])).await.unwrap();
+    stream.write_all(&BODY[..5]).await.unwrap();
+    tokio::time::sleep(pause).await;
+    stream.write_all(&BODY[5..]).await.unwrap();
+  }).await
+}
+
+#[tokio::test]
+async fn read_timeout_ignores_a_slow_consumer() {
+  use futures::StreamExt;
+
+  let address = two_halves(Duration::from_millis(10)).await;
+  let mut downloader = downloader(address);
+  downloader.retry(RetryPolicy::never()).read_timeout(Duration::from_millis(100));
+  let (_, mut stream) = downloader.stream(Body::empty()).await.unwrap();
+  // The response has been received long before the body is read
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let mut received = vec![];
+  while let Some(chunk) = stream.next().await {
+    received.extend_from_slice(&chunk.unwrap());
+    tokio::time::sleep(Duration::from_millis(300)).await;
+  }
+  assert_eq!(received, BODY);
+}
+
+#[tokio::test]
+async fn read_timeout_fails_a_stalled_server() {
+  let address = two_halves(Duration::from_secs(5)).await;
+  let mut downloader = downloader(address);
+  downloader.retry(RetryPolicy::never()).read_timeout(Duration::from_millis(100));
+  let mut buffer = vec![];
+  let result = downloader.download(Body::empty(), &mut buffer).await;
+  assert!(matches!(result, Err(Error::TimedOut(download_async::Timeout::Read))), "expected a read timeout, got {:?}", result);
+  assert_eq!(buffer, &BODY[..5]);
+}
+
+#[tokio::test]
+async fn connect_timeout_fails_an_unreachable_server() {
+  let (address, _queued, _listener) = common::unreachable("127.0.0.1:0".parse().unwrap()).await.unwrap();
+  let mut downloader = downloader(address);
+  downloader.retry(RetryPolicy::never()).connect_timeout(Duration::from_millis(100));
+  let started = std::time::Instant::now();
+  let result = downloader.download(Body::empty(), &mut vec![]).await;
+  assert!(matches!(result, Err(Error::TimedOut(download_async::Timeout::Connect))), "expected a connect timeout, got {:?}", result);
+  assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn total_timeout_fails_a_dribbling_server() {
+  // Every byte arrives well within the read timeout, but the whole body takes a second
+  let address = serve(|_, mut stream| async move {
+    stream.write_all(&response("200 OK", &[("Content-Length", "10")])).await.unwrap();
+    for byte in BODY {
+      stream.write_all(&[*byte]).await.unwrap();
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+  }).await;
+  let mut downloader = downloader(address);
+  downloader.read_timeout(Duration::from_millis(300)).total_timeout(Duration::from_millis(350));
+  let mut buffer = vec![];
+  let result = downloader.download(Body::empty(), &mut buffer).await;
+  assert!(matches!(result, Err(Error::TimedOut(download_async::Timeout::Total))), "expected a total timeout, got {:?}", result);
+  assert!(!buffer.is_empty() && buffer.len() < BODY.len(), "{:?}", buffer);
+}
+
+#[tokio::test]
+async fn server_digest_mismatch_fails_the_download() {
+  // The SHA-256 digest of `BODY`, and of `BODY` with its last byte changed
+  for (digest, matches) in [("hNiYd/DUBB77a/kaFvAkjy/Vc+avBcGflr7bn4gveII=", true), ("3Tkhu9loAMllV8Us1ZDf6Rjs8qq1DYFjuEV/D01CFQE=", false)] {
+    let address = serve(move |_, mut stream| async move {
+      let content_digest = format!("sha-256=:{}:", digest);
+      let mut reply = response("200 OK", &[("Content-Length", "10"), ("Content-Digest", &content_digest)]);
+      reply.extend_from_slice(BODY);
+      stream.write_all(&reply).await.unwrap();
+    }).await;
+    let mut downloader = downloader(address);
+    downloader.verify_server_digests();
+    let mut buffer = vec![];
+    let result = downloader.download(Body::empty(), &mut buffer).await;
+    match matches {
+      true => assert!(result.is_ok(), "expected the digest to match, got {:?}", result),
+      false => assert!(matches!(result, Err(Error::ChecksumMismatch { algorithm: download_async::Algorithm::Sha256, .. })), "expected Error::ChecksumMismatch, got {:?}", result)
+    }
+  }
+}
+
+#[tokio::test]
+async fn expected_digest_mismatch_isnt_retried() {
+  let requests = Arc::new(Mutex::new(0));
+  let counter = requests.clone();
+  let address = serve(move |_, mut stream| {
+    *counter.lock().unwrap() += 1;
+    async move {
+      let mut reply = response("200 OK", &[("Content-Length", "10")]);
+      reply.extend_from_slice(BODY);
+      stream.write_all(&reply).await.unwrap();
+    }
+  }).await;
+  let mut downloader = downloader(address);
+  // The SHA-256 digest of `BODY` with its last byte changed
+  downloader.expect_digest(download_async::Algorithm::Sha256, "dd3921bbd96800c96557c52cd590dfe918ecf2aab50d8163b8457f0f4d421501").unwrap();
+  let mut buffer = vec![];
+  let result = downloader.download(Body::empty(), &mut buffer).await;
+  assert!(matches!(result, Err(Error::ChecksumMismatch { algorithm: download_async::Algorithm::Sha256, .. })), "expected Error::ChecksumMismatch, got {:?}", result);
+  assert_eq!(*requests.lock().unwrap(), 1);
+}
+
+#[test]
+fn invalid_expected_digests() {
+  let mut downloader = Downloader::new();
+  assert!(matches!(downloader.expect_digest(download_async::Algorithm::Sha256, "not hex"), Err(Error::InvalidDigest(_))));
+  assert!(downloader.expect_digest(download_async::Algorithm::Md5, "dd3921bbd96800c96557c52cd590dfe9").is_ok());
+  assert!(matches!(downloader.expect_digest(download_async::Algorithm::Sha1, "dd3921bbd96800c96557c52cd590dfe9"), Err(Error::InvalidDigest(_))));
+}
+
+#[tokio::test]
+async fn download_async_to_a_file() {
+  let address = two_halves(Duration::from_millis(10)).await;
+  let directory = std::env::temp_dir().join(format!("download-async-async-file-{}", std::process::id()));
+  std::fs::create_dir_all(&directory).unwrap();
+  let path = directory.join("file");
+  let mut file = tokio::fs::File::create(&path).await.unwrap();
+  downloader(address).download_async(Body::empty(), &mut file).await.unwrap();
+  drop(file);
+  assert_eq!(std::fs::read(&path).unwrap(), BODY);
+  std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn download_async_waits_for_a_slow_reader() {
+  use tokio::io::AsyncReadExt;
+
+  let address = two_halves(Duration::from_millis(10)).await;
+  // The pipe holds less than the body, so the download only completes while it's read
+  let (mut writer, mut reader) = tokio::io::duplex(4);
+  let reading = tokio::spawn(async move {
+    let mut received = vec![];
+    let mut chunk = [0; 3];
+    loop {
+      tokio::time::sleep(Duration::from_millis(20)).await;
+      match reader.read(&mut chunk).await.unwrap() {
+        0 => return received,
+        read => received.extend_from_slice(&chunk[..read])
+      }
+    }
+  });
+  downloader(address).download_async(Body::empty(), &mut writer).await.unwrap();
+  drop(writer);
+  assert_eq!(reading.await.unwrap(), BODY);
+}
+
+#[tokio::test]
+async fn async_read_reads_the_body() {
+  use tokio::io::AsyncReadExt;
+
+  let address = two_halves(Duration::from_millis(10)).await;
+  let (parts, mut reader) = downloader(address).into_async_read(Body::empty()).await.unwrap();
+  assert_eq!(parts.status, 200);
+  let mut received = vec![];
+  reader.read_to_end(&mut received).await.unwrap();
+  assert_eq!(received, BODY);
+}
+
+#[tokio::test]
+async fn async_read_fails_with_an_io_error() {
+  use tokio::io::AsyncReadExt;
+
+  // Stalls after the first half of the body
+  let address = two_halves(Duration::from_secs(60)).await;
+  let mut stalled = downloader(address);
+  stalled.retry(RetryPolicy::never()).read_timeout(Duration::from_millis(100));
+  let (_, mut reader) = stalled.into_async_read(Body::empty()).await.unwrap();
+  let mut received = vec![];
+  let error = reader.read_to_end(&mut received).await.unwrap_err();
+  assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+  assert!(matches!(error.get_ref().and_then(|inner| inner.downcast_ref::<Error>()), Some(Error::TimedOut(download_async::Timeout::Read))), "{:?}", error);
+  assert_eq!(received, &BODY[..5]);
+
+  // Closes the connection after the first half of the body
+  let address = serve(|_, mut stream| async move {
+    stream.write_all(&response("200 OK", &[("Content-Length", "10")])).await.unwrap();
+    stream.write_all(&BODY[..5]).await.unwrap();
+  }).await;
+  let mut closed = downloader(address);
+  closed.retry(RetryPolicy::never());
+  let (_, mut reader) = closed.into_async_read(Body::empty()).await.unwrap();
+  let error = reader.read_to_end(&mut vec![]).await.unwrap_err();
+  assert!(error.get_ref().is_some_and(|inner| inner.is::<Error>()), "{:?}", error);
+}
+
+/// Answers every request with `body` and the Content-Encoding `encoding`, recording the requests.
+async fn serve_encoded(encoding: &'static str, body: &'static [u8]) -> (SocketAddr, Arc<Mutex<Vec<Head>>>) {
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  let address = serve(move |head, mut stream| {
+    received.lock().unwrap().push(head);
+    async move {
+      let length = body.len().to_string();
+      let reply = [response("200 OK", &[("Content-Encoding", encoding), ("Content-Length", &length)]), body.to_vec()].concat();
+      stream.write_all(&reply).await.unwrap();
+    }
+  }).await;
+  (address, requests)
+}
+
+#[tokio::test]
+async fn accept_encoding_lists_the_enabled_encodings() {
+  let (address, requests) = serve_encoded("identity", BODY).await;
+  let mut buffer = vec![];
+  downloader(address).download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, BODY);
+
+  let enabled = [(cfg!(feature = "gzip"), "gzip"), (cfg!(feature = "brotli"), "br"), (cfg!(feature = "zstd"), "zstd"), (cfg!(feature = "deflate"), "deflate")];
+  let encodings: Vec<&str> = enabled.iter().filter(|(enabled, _)| *enabled).map(|(_, encoding)| *encoding).collect();
+  // Without any compression feature the header is left out
+  let expected = Some(encodings.join(", ")).filter(|encodings| !encodings.is_empty());
+  assert_eq!(requests.lock().unwrap()[0].header("Accept-Encoding"), expected.as_deref());
+}
+
+#[cfg(feature = "zstd")]
+#[tokio::test]
+async fn zstd_bodies_are_decoded() {
+  // A zstd frame of 1005 bytes, with a block repeating `a` 1000 times and a raw block with `hello`
+  const FRAME: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd, 0x60, 0xed, 0x02, 0x42, 0x1f, 0x00, b'a', 0x29, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o'];
+  let (address, _) = serve_encoded("zstd", FRAME).await;
+  let mut buffer = vec![];
+  downloader(address).download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, [vec![b'a'; 1000], b"hello".to_vec()].concat());
+}
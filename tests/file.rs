@@ -0,0 +1,134 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use download_async::{Body, Downloader};
+use download_async::http::Uri;
+use common::{response, serve, Head};
+
+const BODY: &[u8] = b"0123456789abcdefghij";
+
+/// Serves `BODY` with the ETag `"v1"`, in ranges when asked for.
+///
+/// While `stall` is set, only the first half of every response body is sent before the server stops responding.
+async fn server(stall: Arc<AtomicBool>) -> (SocketAddr, Arc<Mutex<Vec<Head>>>) {
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  let address = serve(move |head, mut stream| {
+    received.lock().unwrap().push(head.clone());
+    let stall = stall.load(Ordering::SeqCst);
+    async move {
+      let range = head.header("Range").and_then(|range| {
+        let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+        Some((first.parse::<usize>().ok()?, last.parse::<usize>().ok().unwrap_or(BODY.len() - 1)))
+      });
+      let (first, last) = range.unwrap_or((0, BODY.len() - 1));
+      let length = (last + 1 - first).to_string();
+      let mut reply = match range {
+        Some(_) => response("206 Partial Content", &[("ETag", "\"v1\""), ("Content-Range", &format!("bytes {}-{}/{}", first, last, BODY.len())), ("Content-Length", &length)]),
+        None => response("200 OK", &[("ETag", "\"v1\""), ("Content-Length", &length)]),
+      };
+      let body = &BODY[first..=last];
+      // The probe for range support is always answered
+      if stall && body.len() > 1 {
+        reply.extend_from_slice(&body[..body.len() / 2]);
+        stream.write_all(&reply).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+      } else {
+        reply.extend_from_slice(body);
+        stream.write_all(&reply).await.unwrap();
+      }
+    }
+  }).await;
+  (address, requests)
+}
+
+fn downloader(address: SocketAddr, segments: usize) -> Downloader {
+  let mut downloader = Downloader::new();
+  downloader.use_uri(format!("http://{}/file", address).parse::<Uri>().unwrap()).allow_http().segments(segments);
+  downloader
+}
+
+/// A path in a new temporary directory, to download to.
+fn destination(name: &str) -> PathBuf {
+  let directory = std::env::temp_dir().join(format!("download-async-{}-{}", name, std::process::id()));
+  let _ = std::fs::remove_dir_all(&directory);
+  std::fs::create_dir_all(&directory).unwrap();
+  directory.join("file")
+}
+
+fn with_suffix(path: &std::path::Path, suffix: &str) -> PathBuf {
+  let mut path = path.as_os_str().to_owned();
+  path.push(suffix);
+  PathBuf::from(path)
+}
+
+/// Starts downloading to `path`, and drops the download before it completes like a killed process would.
+async fn kill_midway(downloader: Downloader, path: &std::path::Path) {
+  // Long enough for the journal to be saved at least once
+  let result = tokio::time::timeout(Duration::from_millis(2500), downloader.download_to_path(Body::empty(), path)).await;
+  assert!(result.is_err(), "the download completed before it was killed: {:?}", result);
+}
+
+#[tokio::test]
+async fn killed_download_resumes() {
+  let stall = Arc::new(AtomicBool::new(true));
+  let (address, requests) = server(stall.clone()).await;
+  let path = destination("killed");
+  kill_midway(downloader(address, 1), &path).await;
+
+  let journal = std::fs::read_to_string(with_suffix(&path, ".part.journal")).unwrap();
+  assert!(journal.contains("etag \"v1\"\n"), "{}", journal);
+  assert!(journal.contains("range 0 10\n"), "{}", journal);
+
+  stall.store(false, Ordering::SeqCst);
+  downloader(address, 1).download_to_path(Body::empty(), &path).await.unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), BODY);
+  assert!(!with_suffix(&path, ".part").exists());
+  assert!(!with_suffix(&path, ".part.journal").exists());
+
+  let requests = requests.lock().unwrap();
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[1].header("Range"), Some("bytes=10-"));
+  assert_eq!(requests[1].header("If-Range"), Some("\"v1\""));
+  std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn killed_segmented_download_resumes_the_gaps() {
+  let stall = Arc::new(AtomicBool::new(true));
+  let (address, requests) = server(stall.clone()).await;
+  let path = destination("killed-segmented");
+  kill_midway(downloader(address, 2), &path).await;
+
+  // Only half of every segment was written, the gaps in between aren't claimed
+  let journal = std::fs::read_to_string(with_suffix(&path, ".part.journal")).unwrap();
+  assert!(journal.contains("range 0 5\nrange 10 15\n"), "{}", journal);
+
+  stall.store(false, Ordering::SeqCst);
+  let resumed = requests.lock().unwrap().len();
+  downloader(address, 2).download_to_path(Body::empty(), &path).await.unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), BODY);
+
+  let requests = requests.lock().unwrap();
+  let mut ranges: Vec<_> = requests[resumed..].iter().filter_map(|head| head.header("Range")).collect();
+  ranges.sort_unstable();
+  assert_eq!(ranges, ["bytes=0-0", "bytes=15-19", "bytes=5-9"]);
+  std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn part_file_without_journal_starts_over() {
+  let (address, requests) = server(Arc::new(AtomicBool::new(false))).await;
+  let path = destination("no-journal");
+  std::fs::write(with_suffix(&path, ".part"), b"XXXXXXXXXXXXXXX").unwrap();
+
+  downloader(address, 1).download_to_path(Body::empty(), &path).await.unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), BODY);
+  assert_eq!(requests.lock().unwrap()[0].header("Range"), None);
+  std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
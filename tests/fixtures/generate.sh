@@ -0,0 +1,47 @@
+#!/bin/sh
+# Generates the certificates the TLS tests run against, valid for a century.
+set -e
+cd "$(dirname "$0")"
+days=36500
+
+# The root certificate both the server and the client certificates are issued by
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -keyout ca.key -out ca.pem -days $days \
+  -subj "/CN=download-async test CA" -addext "basicConstraints=critical,CA:true" -addext "keyUsage=critical,keyCertSign"
+
+# The server, for localhost and 127.0.0.1
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -keyout server.key -out server.csr -subj "/CN=localhost"
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -out server.pem -days $days \
+  -extfile /dev/stdin <<EXT
+basicConstraints=critical,CA:false
+subjectAltName=DNS:localhost,IP:127.0.0.1
+extendedKeyUsage=serverAuth
+EXT
+
+# The clients, with an RSA key in PKCS#8 and PKCS#1 and an EC key in SEC1
+client() {
+  openssl req -new -key "$1.key" -out "$1.csr" -subj "/CN=$1"
+  openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial -out "$1.crt" -days $days \
+    -extfile /dev/stdin <<EXT
+basicConstraints=critical,CA:false
+extendedKeyUsage=clientAuth
+EXT
+}
+openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out client-rsa.key
+client client-rsa
+cat client-rsa.crt client-rsa.key > client-pkcs8.pem
+openssl pkey -in client-rsa.key -traditional | cat client-rsa.crt - > client-pkcs1.pem
+openssl ecparam -name prime256v1 -genkey -noout -out client-ec.key
+client client-ec
+cat client-ec.crt client-ec.key > client-sec1.pem
+openssl pkcs12 -export -in client-rsa.crt -inkey client-rsa.key -passout pass:password -out client.p12
+
+# Keys neither backend accepts: a curve rustls can't sign with, and an encrypted key
+openssl ecparam -name secp521r1 -genkey -noout | cat client-ec.crt - > client-p521.pem
+openssl pkcs8 -topk8 -in client-rsa.key -v2 aes256 -passout pass:password | cat client-rsa.crt - > client-encrypted.pem
+
+# The base64 encoded SHA-256 hashes of the EC public key of the server and the RSA public key of a client, as `Downloader::pin_public_key` documents it
+for certificate in server client-pkcs8; do
+  openssl x509 -in $certificate.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64 > $certificate.sha256
+done
+
+rm -f ./*.csr ./*.srl ca.key client-rsa.key client-ec.key client-rsa.crt client-ec.crt
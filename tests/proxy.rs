@@ -0,0 +1,110 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use download_async::{Body, Downloader, Error, Proxy};
+use download_async::http::Uri;
+use common::{read_head, response, serve, Head};
+
+/// `user:password` in base64.
+const AUTHORIZATION: &str = "Basic dXNlcjpwYXNzd29yZA==";
+
+/// A proxy that records the heads of the requests it receives, and answers them with `reply`.
+async fn proxy(reply: &'static [u8]) -> (SocketAddr, Arc<Mutex<Vec<Head>>>) {
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  let address = serve(move |head, mut stream| {
+    received.lock().unwrap().push(head);
+    async move {
+      stream.write_all(reply).await.unwrap();
+    }
+  }).await;
+  (address, requests)
+}
+
+fn downloader(uri: &'static str, proxy: SocketAddr) -> Downloader {
+  let mut proxy = Proxy::new(format!("http://{}", proxy).parse::<Uri>().unwrap());
+  proxy.basic_auth("user", "password");
+  let mut downloader = Downloader::new();
+  downloader.use_uri(Uri::from_static(uri)).proxy(proxy);
+  downloader
+}
+
+#[tokio::test]
+async fn https_is_tunnelled_with_connect() {
+  // The tunnel closes right away, so the TLS handshake fails
+  let (address, requests) = proxy(b"HTTP/1.1 200 Connection established\r\n\r\n").await;
+  let mut buffer = vec![];
+  let result = downloader("https://example.test/file", address).download(Body::empty(), &mut buffer).await;
+  assert!(result.is_err());
+
+  let requests = requests.lock().unwrap();
+  assert_eq!(requests[0].line, "CONNECT example.test:443 HTTP/1.1");
+  assert_eq!(requests[0].header("Host"), Some("example.test:443"));
+  assert_eq!(requests[0].header("Proxy-Authorization"), Some(AUTHORIZATION));
+}
+
+#[tokio::test]
+async fn refused_connect_is_a_proxy_error() {
+  let (address, _) = proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n").await;
+  let mut buffer = vec![];
+  match downloader("https://example.test:8443/file", address).download(Body::empty(), &mut buffer).await {
+    Err(Error::ProxyError(message)) => assert!(message.contains("example.test:8443") && message.contains("407"), "{}", message),
+    result => panic!("expected Error::ProxyError, got {:?}", result)
+  }
+}
+
+#[tokio::test]
+async fn http_is_sent_to_the_proxy_in_absolute_form() {
+  let reply = [response("200 OK", &[("Content-Length", "5")]), b"hello".to_vec()].concat();
+  let (address, requests) = proxy(Box::leak(reply.into_boxed_slice())).await;
+  let mut downloader = downloader("http://example.test/file?query", address);
+  downloader.allow_http();
+  let mut buffer = vec![];
+  downloader.download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, b"hello");
+
+  let requests = requests.lock().unwrap();
+  assert_eq!(requests[0].line, "GET http://example.test/file?query HTTP/1.1");
+  assert_eq!(requests[0].header("Proxy-Authorization"), Some(AUTHORIZATION));
+}
+
+#[tokio::test]
+async fn socks5_with_credentials_accepts_no_authentication() {
+  // A SOCKS5 proxy that doesn't require authentication, and answers the request itself
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  let server = tokio::spawn(async move {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut greeting = vec![0; 2];
+    stream.read_exact(&mut greeting).await.unwrap();
+    greeting.resize(2 + greeting[1] as usize, 0);
+    stream.read_exact(&mut greeting[2..]).await.unwrap();
+    stream.write_all(&[0x05, 0x00]).await.unwrap();
+    let mut request = [0; 5];
+    stream.read_exact(&mut request).await.unwrap();
+    let mut host = vec![0; request[4] as usize + 2];
+    stream.read_exact(&mut host).await.unwrap();
+    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0]).await.unwrap();
+    let head = read_head(&mut stream).await.unwrap();
+    stream.write_all(&response("200 OK", &[("Content-Length", "5")])).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    (greeting, request, host, head)
+  });
+
+  let mut proxy = Proxy::new(format!("socks5h://{}", address).parse::<Uri>().unwrap());
+  proxy.basic_auth("user", "password");
+  let mut downloader = Downloader::new();
+  downloader.use_uri(Uri::from_static("http://example.test/file")).allow_http().proxy(proxy);
+  let mut buffer = vec![];
+  downloader.download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, b"hello");
+
+  let (greeting, request, host, head) = server.await.unwrap();
+  assert_eq!(greeting, [0x05, 0x02, 0x00, 0x02]);
+  assert_eq!(request, [0x05, 0x01, 0x00, 0x03, 12]);
+  assert_eq!(host, b"example.test\x00\x50");
+  assert_eq!(head.line, "GET /file HTTP/1.1");
+}
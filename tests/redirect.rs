@@ -0,0 +1,99 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::AsyncWriteExt;
+use download_async::{Body, Certificate, Downloader, Error, RedirectChain, RedirectPolicy, RetryPolicy};
+use download_async::http::Uri;
+use common::{response, serve, CA};
+
+/// Downloads from `address` over HTTP, following redirects according to `policy`.
+fn downloader(address: SocketAddr, policy: RedirectPolicy) -> Downloader {
+  let mut downloader = Downloader::new();
+  downloader.use_uri(format!("http://{}/file", address).parse::<Uri>().unwrap()).allow_http().retry(RetryPolicy::never()).redirect(policy);
+  downloader
+}
+
+/// Answers every request with `hello`, counting them.
+async fn hello() -> (SocketAddr, Arc<AtomicUsize>) {
+  let requests = Arc::new(AtomicUsize::new(0));
+  let counter = requests.clone();
+  let address = serve(move |_, mut stream| {
+    counter.fetch_add(1, Ordering::SeqCst);
+    async move {
+      stream.write_all(&[response("200 OK", &[("Content-Length", "5")]), b"hello".to_vec()].concat()).await.unwrap();
+    }
+  }).await;
+  (address, requests)
+}
+
+/// Redirects every request to `location`.
+async fn redirect(location: String) -> SocketAddr {
+  serve(move |_, mut stream| {
+    let reply = response("302 Found", &[("Location", &location), ("Content-Length", "0")]);
+    async move {
+      stream.write_all(&reply).await.unwrap();
+    }
+  }).await
+}
+
+#[tokio::test]
+async fn the_chain_is_in_the_extensions() {
+  let (target, _) = hello().await;
+  let address = redirect(format!("http://{}/final", target)).await;
+  let mut buffer = vec![];
+  let parts = downloader(address, RedirectPolicy::default()).download(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer, b"hello");
+  let chain = parts.extensions.get::<RedirectChain>().unwrap();
+  assert_eq!(chain.redirects(), 1);
+  assert_eq!(chain.uris()[0], format!("http://{}/file", address).parse::<Uri>().unwrap());
+  assert_eq!(chain.final_uri(), &format!("http://{}/final", target).parse::<Uri>().unwrap());
+}
+
+#[tokio::test]
+async fn https_is_never_downgraded() {
+  let (target, requests) = hello().await;
+  let location = format!("http://{}/file", target);
+  let address = common::serve_tls(false, move |_| response("302 Found", &[("Location", &location), ("Content-Length", "0")])).await;
+  let mut downloader = Downloader::new();
+  downloader.use_uri(format!("https://localhost:{}/file", address.port()).parse::<Uri>().unwrap())
+    .resolve("localhost", address)
+    .retry(RetryPolicy::never())
+    .tls_built_in_roots(false)
+    .add_root_certificate(Certificate::from_pem(CA).unwrap());
+  let mut buffer = vec![];
+  let result = downloader.download(Body::empty(), &mut buffer).await;
+  assert!(matches!(result, Err(Error::RedirectRejected(ref uri)) if uri.port_u16() == Some(target.port())), "{:?}", result);
+  assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn same_origin_only() {
+  let (target, requests) = hello().await;
+  let address = redirect(format!("http://{}/file", target)).await;
+  let mut policy = RedirectPolicy::default();
+  policy.same_origin_only(true);
+  let mut buffer = vec![];
+  let result = downloader(address, policy).download(Body::empty(), &mut buffer).await;
+  assert!(matches!(result, Err(Error::RedirectRejected(_))), "{:?}", result);
+  assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn too_many_redirects() {
+  let requests = Arc::new(AtomicUsize::new(0));
+  let counter = requests.clone();
+  let address = serve(move |_, mut stream| {
+    counter.fetch_add(1, Ordering::SeqCst);
+    async move {
+      stream.write_all(&response("302 Found", &[("Location", "/again"), ("Content-Length", "0")])).await.unwrap();
+    }
+  }).await;
+  let mut policy = RedirectPolicy::default();
+  policy.limit(3);
+  let mut buffer = vec![];
+  let result = downloader(address, policy).download(Body::empty(), &mut buffer).await;
+  assert!(matches!(result, Err(Error::TooManyRedirects(3))), "{:?}", result);
+  assert_eq!(requests.load(Ordering::SeqCst), 4);
+}
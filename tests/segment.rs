@@ -0,0 +1,80 @@
+mod common;
+
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use download_async::{Body, Downloader, Error};
+use download_async::http::Uri;
+use common::{response, serve, Head};
+
+const BODY: &[u8] = b"0123456789abcdefghij";
+
+/// The first and last byte of the `Range` of a request.
+fn range(head: &Head) -> Option<(usize, usize)> {
+  let (first, last) = head.header("Range")?.strip_prefix("bytes=")?.split_once('-')?;
+  Some((first.parse().ok()?, last.parse().ok()?))
+}
+
+/// Serves `BODY` in ranges, with the ETag `"v1"` for the probe and `segment_etag` for the segments.
+///
+/// A segment is answered with the whole body when it's requested with another `If-Range`, unless `ignore_if_range` is set.
+async fn server(segment_etag: &'static str, ignore_if_range: bool) -> (SocketAddr, Arc<Mutex<Vec<Head>>>) {
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  let address = serve(move |head, mut stream| {
+    received.lock().unwrap().push(head.clone());
+    async move {
+      let (first, last) = range(&head).unwrap();
+      let etag = if first == 0 && last == 0 { "\"v1\"" } else { segment_etag };
+      let reply = match head.header("If-Range") {
+        Some(if_range) if if_range != etag && !ignore_if_range => {
+          let mut reply = response("200 OK", &[("ETag", etag), ("Content-Length", &BODY.len().to_string())]);
+          reply.extend_from_slice(BODY);
+          reply
+        },
+        _ => {
+          let content_range = format!("bytes {}-{}/{}", first, last, BODY.len());
+          let length = (last + 1 - first).to_string();
+          let mut reply = response("206 Partial Content", &[("ETag", etag), ("Content-Range", &content_range), ("Content-Length", &length)]);
+          reply.extend_from_slice(&BODY[first..=last]);
+          reply
+        }
+      };
+      stream.write_all(&reply).await.unwrap();
+    }
+  }).await;
+  (address, requests)
+}
+
+fn downloader(address: SocketAddr) -> Downloader {
+  let mut downloader = Downloader::new();
+  downloader.use_uri(format!("http://{}/file", address).parse::<Uri>().unwrap()).allow_http().segments(4);
+  downloader
+}
+
+#[tokio::test]
+async fn segments_are_requested_with_the_probed_version() {
+  let (address, requests) = server("\"v1\"", false).await;
+  let mut buffer = Cursor::new(vec![]);
+  downloader(address).download_segmented(Body::empty(), &mut buffer).await.unwrap();
+  assert_eq!(buffer.into_inner(), BODY);
+
+  let requests = requests.lock().unwrap();
+  assert_eq!(requests.len(), 5);
+  assert_eq!(requests[0].header("Accept-Encoding"), Some("identity"));
+  for segment in &requests[1..] {
+    assert_eq!(segment.header("If-Range"), Some("\"v1\""));
+    assert_eq!(segment.header("Accept-Encoding"), Some("identity"));
+  }
+}
+
+#[tokio::test]
+async fn segments_fail_when_the_resource_changed() {
+  for ignore_if_range in [false, true] {
+    let (address, _) = server("\"v2\"", ignore_if_range).await;
+    let mut buffer = Cursor::new(vec![]);
+    let result = downloader(address).download_segmented(Body::empty(), &mut buffer).await;
+    assert!(matches!(result, Err(Error::ResourceChanged)), "expected Error::ResourceChanged, got {:?}", result);
+  }
+}